@@ -1,6 +1,7 @@
 pub mod middleware;
 pub mod utils;
 use chrono::{DateTime, Utc};
+use jwt_simple::claims::JWTClaims;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use utoipa::ToSchema;
@@ -14,6 +15,10 @@ pub struct User {
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    /// when the email address was verified, None if not verified yet
+    #[sqlx(default)]
+    #[schema(value_type=Option<String>)]
+    pub verified_at: Option<DateTime<Utc>>,
     #[schema(value_type=String)]
     pub created_at: DateTime<Utc>,
 }
@@ -34,6 +39,10 @@ pub struct Chat {
     pub r#type: ChatType,
     pub name: Option<String>,
     pub members: Vec<i64>,
+    /// archived chats are read-only and hidden from the default list
+    #[serde(default)]
+    #[schema(value_type=Option<String>)]
+    pub archived_at: Option<DateTime<Utc>>,
     #[schema(value_type=String)]
     pub created_at: DateTime<Utc>,
 }
@@ -59,6 +68,210 @@ pub struct Message {
     pub created_at: DateTime<Utc>,
 }
 
+/// what a personal access token is allowed to do, stored as text like `messages:write`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq, Eq, Hash, ToSchema)]
+#[sqlx(type_name = "text")]
+pub enum Scope {
+    #[serde(rename = "chats:read")]
+    #[sqlx(rename = "chats:read")]
+    ChatsRead,
+    #[serde(rename = "chats:write")]
+    #[sqlx(rename = "chats:write")]
+    ChatsWrite,
+    #[serde(rename = "messages:read")]
+    #[sqlx(rename = "messages:read")]
+    MessagesRead,
+    #[serde(rename = "messages:write")]
+    #[sqlx(rename = "messages:write")]
+    MessagesWrite,
+    #[serde(rename = "files:read")]
+    #[sqlx(rename = "files:read")]
+    FilesRead,
+    #[serde(rename = "files:write")]
+    #[sqlx(rename = "files:write")]
+    FilesWrite,
+    #[serde(rename = "users:read")]
+    #[sqlx(rename = "users:read")]
+    UsersRead,
+}
+
+impl Scope {
+    /// a signed in session is granted all the scopes
+    pub fn all() -> Vec<Scope> {
+        vec![
+            Scope::ChatsRead,
+            Scope::ChatsWrite,
+            Scope::MessagesRead,
+            Scope::MessagesWrite,
+            Scope::FilesRead,
+            Scope::FilesWrite,
+            Scope::UsersRead,
+        ]
+    }
+}
+
+impl sqlx::postgres::PgHasArrayType for Scope {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_text")
+    }
+}
+
+/// role of the user in its workspace, ordered from the least privileged
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    sqlx::Type,
+    ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    /// can only take part in the chats it's added to
+    Guest,
+    #[default]
+    Member,
+    Admin,
+    Owner,
+}
+
+/// role of the user in a chat, the creator of a chat is its owner
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ChatMemberRole {
+    #[default]
+    Member,
+    Owner,
+}
+
+/// what a workspace role is allowed to do, see `WorkspaceAction::min_role` for the matrix
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceAction {
+    CreatePublicChannel,
+    /// join the public channels of the workspace
+    JoinPublicChannel,
+    /// see the details of the workspace and rename it
+    ManageWorkspace,
+    Invite,
+    RemoveUser,
+    /// rename any chat and remove its members
+    ManageChats,
+    /// archive chats, or delete them with their messages and files
+    DeleteChat,
+    /// deactivate and reactivate users with a lower role
+    DeactivateUser,
+    DeleteOthersMessages,
+    /// change the role of users with a lower role, up to the role below its own
+    ManageRoles,
+}
+
+impl WorkspaceAction {
+    /// the least privileged role allowed to do it
+    pub fn min_role(self) -> WorkspaceRole {
+        match self {
+            WorkspaceAction::CreatePublicChannel => WorkspaceRole::Member,
+            WorkspaceAction::JoinPublicChannel => WorkspaceRole::Member,
+            WorkspaceAction::ManageWorkspace => WorkspaceRole::Admin,
+            WorkspaceAction::Invite => WorkspaceRole::Admin,
+            WorkspaceAction::RemoveUser => WorkspaceRole::Admin,
+            WorkspaceAction::ManageChats => WorkspaceRole::Admin,
+            WorkspaceAction::DeleteChat => WorkspaceRole::Admin,
+            WorkspaceAction::DeactivateUser => WorkspaceRole::Admin,
+            WorkspaceAction::DeleteOthersMessages => WorkspaceRole::Admin,
+            WorkspaceAction::ManageRoles => WorkspaceRole::Admin,
+        }
+    }
+}
+
+impl WorkspaceRole {
+    pub fn can(self, action: WorkspaceAction) -> bool {
+        self >= action.min_role()
+    }
+}
+
+/// custom claims of the access token
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserClaims {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default)]
+    pub role: WorkspaceRole,
+    #[serde(default = "Scope::all")]
+    pub scopes: Vec<Scope>,
+    /// the signin session(device) the token is issued for
+    #[serde(default, rename = "sid", skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<i64>,
+}
+
+impl From<User> for UserClaims {
+    /// a member session, use it only if the role doesn't matter
+    fn from(user: User) -> Self {
+        Self {
+            user,
+            role: WorkspaceRole::default(),
+            scopes: Scope::all(),
+            session_id: None,
+        }
+    }
+}
+
+/// what the token of a request is allowed to do, inserted by `verify_token` along with the `User`.
+/// It can also be used as an extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub role: WorkspaceRole,
+    pub scopes: Vec<Scope>,
+    /// a personal access token, it can't manage the account
+    pub personal_access_token: bool,
+    /// the signin session of the access token
+    pub session_id: Option<i64>,
+    /// the jwt id(jti) and expiration of the access token, to revoke it
+    pub jwt_id: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Principal {
+    pub fn session(role: WorkspaceRole) -> Self {
+        Self {
+            role,
+            scopes: Scope::all(),
+            personal_access_token: false,
+            session_id: None,
+            jwt_id: None,
+            expires_at: None,
+        }
+    }
+
+    /// the role is checked by the caller, it may have changed since the token was signed
+    pub fn from_claims(role: WorkspaceRole, claims: &JWTClaims<UserClaims>) -> Self {
+        Self {
+            role,
+            scopes: claims.custom.scopes.clone(),
+            personal_access_token: false,
+            session_id: claims.custom.session_id,
+            jwt_id: claims.jwt_id.clone(),
+            expires_at: claims
+                .expires_at
+                .and_then(|exp| DateTime::from_timestamp(exp.as_secs() as _, 0)),
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
 // #[cfg(test)]
 impl User {
     pub fn new(id: i64, email: &str, fullname: &str, password: &str) -> Self {
@@ -68,6 +281,7 @@ impl User {
             email: email.to_string(),
             fullname: fullname.to_string(),
             password_hash: Some(password.to_string()),
+            verified_at: None,
             created_at: Utc::now(),
         }
     }
@@ -41,10 +41,11 @@ where
                 }
             }
         };
-    let req = match state.verify(&token) {
-        Ok(user) => {
+    let req = match state.verify_principal(&token).await {
+        Ok((user, principal)) => {
             let mut req = Request::from_parts(parts, body);
             req.extensions_mut().insert(user);
+            req.extensions_mut().insert(principal);
             req
         }
         Err(e) => {
@@ -80,7 +81,7 @@ mod tests {
     }
     impl TokenVerify for AppState {
         type Error = ();
-        fn verify(&self, token: &str) -> Result<User, ()> {
+        async fn verify(&self, token: &str) -> Result<User, ()> {
             let user = self.0.dk.verify(token);
             user.map_err(|_| ())
         }
@@ -1,17 +1,36 @@
 pub mod auth;
+pub mod permission;
 pub mod request_id;
 pub mod server_time;
-use crate::User;
+use crate::{Principal, User};
 
 use self::{request_id::set_request_id, server_time::ServerTimeLayer};
 use axum::{middleware::from_fn, Router};
+use std::future::Future;
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME_HEADER: &str = "x-server-time";
 
+/// verify the token and return the user, implementors can also check
+/// whether the token has been revoked (which usually needs a database lookup)
 pub trait TokenVerify {
     type Error: std::fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    fn verify(&self, token: &str) -> impl Future<Output = Result<User, Self::Error>> + Send;
+
+    /// verify the token and return the user with the role and scopes granted to the token,
+    /// the default is a member session, implement it if the routes check permissions
+    fn verify_principal(
+        &self,
+        token: &str,
+    ) -> impl Future<Output = Result<(User, Principal), Self::Error>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let user = self.verify(token).await?;
+            Ok((user, Principal::session(Default::default())))
+        }
+    }
 }
 use tower::ServiceBuilder;
 use tower_http::{
@@ -0,0 +1,239 @@
+//! declare the permission a route requires, it must be used after `verify_token`
+
+use crate::{Principal, Scope, WorkspaceAction, WorkspaceRole};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::warn;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// the token is granted the scope
+    Scope(Scope),
+    /// the user has the role or a higher one
+    Role(WorkspaceRole),
+    /// the role of the user is allowed to do it
+    Action(WorkspaceAction),
+    /// a signed in session, not a personal access token
+    Session,
+}
+
+/// the structured 403 response body
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct PermissionDenied {
+    pub error: String,
+    pub required: Permission,
+}
+
+impl Principal {
+    pub fn check(&self, permission: Permission) -> Result<(), PermissionDenied> {
+        let allowed = match permission {
+            Permission::Scope(scope) => self.has_scope(scope),
+            Permission::Role(role) => self.role >= role,
+            Permission::Action(action) => self.role.can(action),
+            Permission::Session => !self.personal_access_token,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(PermissionDenied::new(permission))
+        }
+    }
+}
+
+impl PermissionDenied {
+    pub fn new(required: Permission) -> Self {
+        Self {
+            error: format!("Permission denied: {:?} is required", required),
+            required,
+        }
+    }
+}
+
+impl std::fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.error)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+impl IntoResponse for PermissionDenied {
+    fn into_response(self) -> Response {
+        warn!(self.error);
+        (StatusCode::FORBIDDEN, Json(self)).into_response()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or((StatusCode::UNAUTHORIZED, "token is not verified"))
+    }
+}
+
+#[derive(Clone)]
+pub struct RequirePermissionLayer(Permission);
+
+/// e.g. `post(send_message_handler.layer(require_permission(Permission::Scope(Scope::MessagesWrite))))`
+pub fn require_permission(permission: Permission) -> RequirePermissionLayer {
+    RequirePermissionLayer(permission)
+}
+
+pub fn require_scope(scope: Scope) -> RequirePermissionLayer {
+    RequirePermissionLayer(Permission::Scope(scope))
+}
+
+pub fn require_role(role: WorkspaceRole) -> RequirePermissionLayer {
+    RequirePermissionLayer(Permission::Role(role))
+}
+
+pub fn require_action(action: WorkspaceAction) -> RequirePermissionLayer {
+    RequirePermissionLayer(Permission::Action(action))
+}
+
+impl<S> Layer<S> for RequirePermissionLayer {
+    type Service = RequirePermissionMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequirePermissionMiddleware {
+            inner,
+            permission: self.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequirePermissionMiddleware<S> {
+    inner: S,
+    permission: Permission,
+}
+
+impl<S> Service<Request> for RequirePermissionMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let checked = match request.extensions().get::<Principal>() {
+            Some(principal) => principal.check(self.permission),
+            None => Err(PermissionDenied::new(self.permission)),
+        };
+        if let Err(e) = checked {
+            return Box::pin(async move { Ok(e.into_response()) });
+        }
+        let future = self.inner.call(request);
+        Box::pin(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    async fn handler(principal: Principal) -> String {
+        format!("{:?}", principal.role)
+    }
+
+    fn app(principal: Principal) -> Router {
+        Router::new()
+            .route(
+                "/send",
+                get(handler).layer(require_scope(Scope::MessagesWrite)),
+            )
+            .route(
+                "/admin",
+                get(handler).layer(require_role(WorkspaceRole::Owner)),
+            )
+            .route(
+                "/account",
+                get(handler).layer(require_permission(Permission::Session)),
+            )
+            .route(
+                "/invite",
+                get(handler).layer(require_action(WorkspaceAction::Invite)),
+            )
+            .layer(Extension(principal))
+    }
+
+    async fn status(app: Router, uri: &str) -> Result<StatusCode> {
+        let req = Request::builder().uri(uri).body(Body::empty())?;
+        Ok(app.oneshot(req).await?.status())
+    }
+
+    #[tokio::test]
+    async fn require_permission_should_work() -> Result<()> {
+        let member = Principal::session(WorkspaceRole::Member);
+        assert_eq!(status(app(member.clone()), "/send").await?, StatusCode::OK);
+        assert_eq!(
+            status(app(member.clone()), "/account").await?,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(app(member.clone()), "/admin").await?,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(app(member.clone()), "/invite").await?,
+            StatusCode::FORBIDDEN
+        );
+        let admin = Principal::session(WorkspaceRole::Admin);
+        assert_eq!(status(app(admin.clone()), "/invite").await?, StatusCode::OK);
+        assert_eq!(status(app(admin), "/admin").await?, StatusCode::FORBIDDEN);
+        let owner = Principal::session(WorkspaceRole::Owner);
+        assert_eq!(status(app(owner), "/admin").await?, StatusCode::OK);
+
+        let reader = Principal {
+            role: WorkspaceRole::Owner,
+            scopes: vec![Scope::MessagesRead],
+            personal_access_token: true,
+            session_id: None,
+            jwt_id: None,
+            expires_at: None,
+        };
+        assert_eq!(
+            status(app(reader.clone()), "/account").await?,
+            StatusCode::FORBIDDEN
+        );
+        let req = Request::builder().uri("/send").body(Body::empty())?;
+        let res = app(reader).oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let body: PermissionDenied = serde_json::from_slice(&bytes)?;
+        assert_eq!(body.required, Permission::Scope(Scope::MessagesWrite));
+        Ok(())
+    }
+}
@@ -1,43 +1,166 @@
 #![allow(unused)]
-use crate::User;
-use jwt_simple::prelude::*;
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
+use crate::{User, UserClaims};
+use chrono::{DateTime, Utc};
+use jwt_simple::{prelude::*, JWTError};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+/// access token is short-lived, use refresh token to get a new one
+const JWT_DURATION: u64 = 60 * 15;
 const JWT_AUDIENCE: &str = "chat_web";
 const JWT_ISSUER: &str = "chat_server";
 
 pub struct EncodingKey(Ed25519KeyPair);
-pub struct DecodingKey(Ed25519PublicKey);
+
+/// a key ring of public keys, a token is verified by the key matching its `kid` header,
+/// so the signing key can be rotated without invalidating the tokens signed by the old one.
+#[derive(Default)]
+pub struct DecodingKey(HashMap<String, RingKey>);
+
+struct RingKey {
+    key: Ed25519PublicKey,
+    /// the key is retired and no longer accepted after this time
+    retire_at: Option<DateTime<Utc>>,
+}
+
+/// JSON Web Key Set, used to publish the public keys to other services
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    pub r#use: String,
+    pub kid: String,
+    /// base64url encoded raw public key
+    pub x: String,
+}
 
 impl EncodingKey {
+    /// load the signing key, its key id is the sha256 thumbprint of the public key
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519KeyPair::from_pem(pem)?))
+        let key = Ed25519KeyPair::from_pem(pem)?;
+        let kid = key.public_key().sha256_thumbprint();
+        Ok(Self(key.with_key_id(&kid)))
+    }
+
+    pub fn key_id(&self) -> &str {
+        self.0.key_id().as_deref().unwrap_or_default()
     }
 
-    pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
-        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(JWT_DURATION));
-        let claims = claims.with_issuer("chat_server").with_audience("chat_web");
+    /// sign an access token for user, every token has an unique jwt id(jti) so it can be revoked
+    pub fn sign(&self, claims: impl Into<UserClaims>) -> Result<String, jwt_simple::Error> {
+        let claims = Claims::with_custom_claims(claims.into(), Duration::from_secs(JWT_DURATION));
+        let claims = claims
+            .with_issuer(JWT_ISSUER)
+            .with_audience(JWT_AUDIENCE)
+            .with_jwt_id(uuid::Uuid::now_v7());
         self.0.sign(claims)
     }
 }
 
 impl DecodingKey {
+    /// load a key ring with a single public key
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+        let mut dk = Self(HashMap::new());
+        dk.add_key(pem, None)?;
+        Ok(dk)
     }
+
+    /// add a public key to the key ring and return its key id,
+    /// if `retire_at` is set, tokens signed by this key are rejected after that time
+    pub fn add_key(
+        &mut self,
+        pem: &str,
+        retire_at: Option<DateTime<Utc>>,
+    ) -> Result<String, jwt_simple::Error> {
+        let key = Ed25519PublicKey::from_pem(pem)?;
+        let kid = key.sha256_thumbprint();
+        self.0.insert(kid.clone(), RingKey { key, retire_at });
+        Ok(kid)
+    }
+
+    pub fn remove_key(&mut self, kid: &str) -> bool {
+        self.0.remove(kid).is_some()
+    }
+
+    /// build the key ring from a JSON Web Key Set, e.g. fetched from chat_server
+    pub fn from_jwks(jwks: &Jwks) -> Result<Self, jwt_simple::Error> {
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            if jwk.kty != "OKP" || jwk.crv != "Ed25519" {
+                continue;
+            }
+            let raw = Base64UrlSafeNoPadding::decode_to_vec(&jwk.x, None)?;
+            let key = Ed25519PublicKey::from_bytes(&raw)?;
+            let kid = key.sha256_thumbprint();
+            keys.insert(
+                kid,
+                RingKey {
+                    key,
+                    retire_at: None,
+                },
+            );
+        }
+        Ok(Self(keys))
+    }
+
+    /// the active public keys as a JSON Web Key Set
+    pub fn jwks(&self) -> Jwks {
+        let mut keys: Vec<_> = self
+            .active_keys()
+            .map(|(kid, key)| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                alg: "EdDSA".to_string(),
+                r#use: "sig".to_string(),
+                kid: kid.clone(),
+                x: Base64UrlSafeNoPadding::encode_to_string(key.to_bytes())
+                    .expect("base64 encode should work"),
+            })
+            .collect();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        Jwks { keys }
+    }
+
     pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
+        let claims = self.verify_claims(token)?;
+        Ok(claims.custom.user)
+    }
+
+    /// verify token and return all the claims, including jwt id(jti), expiration, role and scopes
+    pub fn verify_claims(&self, token: &str) -> Result<JWTClaims<UserClaims>, jwt_simple::Error> {
+        let metadata = Token::decode_metadata(token)?;
+        let kid = metadata.key_id().ok_or(JWTError::MissingJWTKeyIdentifier)?;
+        let (_, key) = self
+            .active_keys()
+            .find(|(id, _)| id.as_str() == kid)
+            .ok_or(JWTError::KeyIdentifierMismatch)?;
         let mut options = VerificationOptions {
             allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
             allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+            required_key_id: Some(kid.to_string()),
             ..Default::default()
         };
-        let claims = self.0.verify_token(token, Some(options))?;
-        Ok(claims.custom)
+        key.verify_token(token, Some(options))
+    }
+
+    fn active_keys(&self) -> impl Iterator<Item = (&String, &Ed25519PublicKey)> {
+        let now = Utc::now();
+        self.0
+            .iter()
+            .filter(move |(_, k)| k.retire_at.is_none_or(|t| t > now))
+            .map(|(kid, k)| (kid, &k.key))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{Scope, WorkspaceRole};
 
     #[test]
     pub fn test_jwt() -> anyhow::Result<()> {
@@ -51,6 +174,7 @@ mod tests {
             fullname: "test".to_string(),
             email: "zackjchen@hkjc.org.hk".into(),
             password_hash: None,
+            verified_at: None,
             created_at: chrono::Utc::now(),
         };
         let token = ek.sign(user_init.clone())?;
@@ -58,6 +182,71 @@ mod tests {
         // assert_eq!(token, "");
 
         assert_eq!(user_init, user);
+
+        let claims = dk.verify_claims(&token)?;
+        assert!(claims.jwt_id.is_some());
+        assert_eq!(claims.custom.role, WorkspaceRole::Member);
+        assert_eq!(claims.custom.scopes, Scope::all());
+        assert_ne!(claims.jwt_id, dk.verify_claims(&ek.sign(user)?)?.jwt_id);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_jwt_with_role_and_scopes() -> anyhow::Result<()> {
+        let ek = EncodingKey::load(include_str!(
+            "../../../chat_server/fixtures/encoding_key.pem"
+        ))?;
+        let dk = DecodingKey::load(include_str!(
+            "../../../chat_server/fixtures/decoding_key.pem"
+        ))?;
+        let claims = UserClaims {
+            user: User::new(1, "zackjchen@hkjc.org.hk", "test", ""),
+            role: WorkspaceRole::Owner,
+            scopes: vec![Scope::ChatsRead],
+            session_id: Some(7),
+        };
+        let token = ek.sign(claims)?;
+        let claims = dk.verify_claims(&token)?.custom;
+        assert_eq!(claims.user.id, 1);
+        assert_eq!(claims.role, WorkspaceRole::Owner);
+        assert_eq!(claims.scopes, vec![Scope::ChatsRead]);
+        assert_eq!(claims.session_id, Some(7));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_key_rotation() -> anyhow::Result<()> {
+        let old_key = Ed25519KeyPair::generate();
+        let old_ek = EncodingKey::load(&old_key.to_pem())?;
+        let new_ek = EncodingKey::load(include_str!(
+            "../../../chat_server/fixtures/encoding_key.pem"
+        ))?;
+        let mut dk = DecodingKey::load(include_str!(
+            "../../../chat_server/fixtures/decoding_key.pem"
+        ))?;
+        let user = User::new(1, "zackjchen@hkjc.org.hk", "test", "");
+        let old_token = old_ek.sign(user.clone())?;
+        let new_token = new_ek.sign(user)?;
+        assert!(dk.verify(&old_token).is_err());
+
+        // the old key is still accepted until it's retired
+        let kid = dk.add_key(&old_key.public_key().to_pem(), None)?;
+        assert_eq!(kid, old_ek.key_id());
+        assert!(dk.verify(&old_token).is_ok());
+        assert!(dk.verify(&new_token).is_ok());
+        assert_eq!(dk.jwks().keys.len(), 2);
+
+        dk.add_key(
+            &old_key.public_key().to_pem(),
+            Some(Utc::now() - chrono::Duration::seconds(1)),
+        )?;
+        assert!(dk.verify(&old_token).is_err());
+        assert_eq!(dk.jwks().keys.len(), 1);
+
+        // key ring can be rebuilt from the published jwks
+        let dk = DecodingKey::from_jwks(&dk.jwks())?;
+        assert!(dk.verify(&new_token).is_ok());
+        assert!(dk.verify(&old_token).is_err());
         Ok(())
     }
 }
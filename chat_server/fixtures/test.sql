@@ -18,12 +18,25 @@ VALUES
 
 
 
-INSERT INTO chats(ws_id, name, type, members)
+INSERT INTO workspace_members(ws_id, user_id)
+SELECT ws_id, id FROM users WHERE id > 1;
+
+
+
+INSERT INTO chats(ws_id, name, type)
+VALUES
+(2, '聊天室1', 'group'), -- id = 2
+(2, NULL, 'single'), -- id = 3
+(2, '聊天室3', 'public_channel'), -- id = 4
+(2, '聊天室4', 'private_channel'); -- id = 5
+
+-- the first member is the owner of the chat
+INSERT INTO chat_members(chat_id, user_id, role)
 VALUES
-(2, '聊天室1', 'group', '{2,3,4,5}'), -- id = 1
-(2, NULL, 'single', '{2,4}'), -- id = 2
-(2, '聊天室3', 'public_channel', '{3,4,5}'), -- id = 3
-(2, '聊天室4', 'private_channel', '{2,3,5}'); -- id = 4
+(2, 2, 'owner'), (2, 3, 'member'), (2, 4, 'member'), (2, 5, 'member'),
+(3, 2, 'owner'), (3, 4, 'member'),
+(4, 3, 'owner'), (4, 4, 'member'), (4, 5, 'member'),
+(5, 2, 'owner'), (5, 3, 'member'), (5, 5, 'member');
 
 
 
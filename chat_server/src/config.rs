@@ -1,4 +1,7 @@
+use crate::{mailer::MailerConfig, oidc::OidcConfig, password::PasswordConfig};
 use anyhow::{bail, Result};
+use chat_core::utils::jwt::DecodingKey;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::{fs, path::PathBuf};
 
@@ -6,6 +9,11 @@ use std::{fs, path::PathBuf};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub mailer: MailerConfig,
+    /// sign in by an OpenID Connect identity provider, disabled if not set
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -19,6 +27,74 @@ pub struct ServerConfig {
 pub struct AuthConfig {
     pub sk: String,
     pub pk: String,
+    /// public keys of the previous signing keys, tokens signed by them
+    /// are still accepted until `retire_at`
+    #[serde(default)]
+    pub retired_keys: Vec<RetiredKey>,
+    /// which routes reject users whose email address is not verified
+    #[serde(default)]
+    pub email_verification: EmailVerification,
+    #[serde(default)]
+    pub signin_throttle: SigninThrottle,
+    /// password strength and the argon2 parameters of new hashes
+    #[serde(default)]
+    pub password: PasswordConfig,
+}
+
+/// limit failed signins per account and per IP
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SigninThrottle {
+    /// the account is locked after this many failures
+    pub account_lockout_threshold: u32,
+    /// the IP is locked after this many failures, for any account
+    pub ip_lockout_threshold: u32,
+    /// how long the lock lasts, failures older than it are forgotten
+    pub lockout_seconds: u64,
+    /// the account waits 1, 2, 4... times of it after each failure
+    pub backoff_base_seconds: u64,
+    pub backoff_max_seconds: u64,
+}
+
+impl Default for SigninThrottle {
+    fn default() -> Self {
+        Self {
+            account_lockout_threshold: 10,
+            ip_lockout_threshold: 100,
+            lockout_seconds: 60 * 15,
+            backoff_base_seconds: 1,
+            backoff_max_seconds: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailVerification {
+    /// unverified users can do everything
+    #[default]
+    Off,
+    /// unverified users can't send messages
+    Messages,
+    /// unverified users can't access any authenticated routes
+    All,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RetiredKey {
+    pub pk: String,
+    pub retire_at: DateTime<Utc>,
+}
+
+impl AuthConfig {
+    /// key ring with the current public key and the retired ones
+    pub fn decoding_key(&self) -> Result<DecodingKey, jwt_simple::Error> {
+        let mut dk = DecodingKey::load(&self.pk)?;
+        for key in &self.retired_keys {
+            dk.add_key(&key.pk, Some(key.retire_at))?;
+        }
+        Ok(dk)
+    }
 }
 
 impl AppConfig {
@@ -3,6 +3,7 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use chat_core::middleware::permission::PermissionDenied;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -43,6 +44,42 @@ pub enum AppError {
     Unauthorized,
     #[error("Chat File error: {0}")]
     ChatFileError(String),
+    #[error("Invalid refresh token: {0}")]
+    InvalidRefreshToken(String),
+    #[error("Token has been revoked")]
+    TokenRevoked,
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("Email address is not verified")]
+    EmailNotVerified,
+    #[error("Invalid two-factor code")]
+    InvalidTotpCode,
+    #[error("Two-factor error: {0}")]
+    TwoFactorError(String),
+    #[error("OIDC error: {0}")]
+    OidcError(String),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("{0}")]
+    PermissionDenied(#[from] PermissionDenied),
+    #[error("Too many signin attempts, retry after {0} seconds")]
+    TooManyAttempts(u64),
+    #[error("Account is locked, retry after {0} seconds")]
+    AccountLocked(u64),
+    #[error("Weak password: {0}")]
+    WeakPassword(String),
+    #[error("Invalid invite: {0}")]
+    InvalidInvite(String),
+    #[error("Workspace already exists: {0}")]
+    WorkspaceAlreadyExists(String),
+    #[error("Current password is incorrect")]
+    IncorrectPassword,
+    #[error("Account is deactivated")]
+    AccountDeactivated,
+    #[error("Chat {0} is archived")]
+    ChatArchived(i64),
+    #[error("Not a member of workspace {0}, an invite is required")]
+    NotWorkspaceMember(i64),
 }
 
 impl IntoResponse for AppError {
@@ -59,8 +96,32 @@ impl IntoResponse for AppError {
             AppError::Unauthorized => axum::http::StatusCode::UNAUTHORIZED,
             AppError::CreateMessageError(_) => axum::http::StatusCode::BAD_REQUEST,
             AppError::ChatFileError(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::InvalidRefreshToken(_) => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::TokenRevoked => axum::http::StatusCode::FORBIDDEN,
+            AppError::InvalidToken(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::EmailNotVerified => axum::http::StatusCode::FORBIDDEN,
+            AppError::InvalidTotpCode => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::TwoFactorError(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::OidcError(_) => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::InvalidInput(_) => axum::http::StatusCode::BAD_REQUEST,
+            // the structured 403 tells which permission is required
+            AppError::PermissionDenied(e) => return e.into_response(),
+            AppError::TooManyAttempts(_) => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::AccountLocked(_) => axum::http::StatusCode::LOCKED,
+            AppError::WeakPassword(_) => axum::http::StatusCode::BAD_REQUEST,
+            AppError::InvalidInvite(_) => axum::http::StatusCode::FORBIDDEN,
+            AppError::WorkspaceAlreadyExists(_) => axum::http::StatusCode::CONFLICT,
+            AppError::IncorrectPassword => axum::http::StatusCode::FORBIDDEN,
+            AppError::AccountDeactivated => axum::http::StatusCode::FORBIDDEN,
+            AppError::ChatArchived(_) => axum::http::StatusCode::CONFLICT,
+            AppError::NotWorkspaceMember(_) => axum::http::StatusCode::FORBIDDEN,
         };
 
-        (status, Json(ErrorOutput::new(self.to_string()))).into_response()
+        let mut res = (status, Json(ErrorOutput::new(self.to_string()))).into_response();
+        if let AppError::TooManyAttempts(secs) | AppError::AccountLocked(secs) = self {
+            res.headers_mut()
+                .insert(axum::http::header::RETRY_AFTER, secs.into());
+        }
+        res
     }
 }
@@ -0,0 +1,172 @@
+use crate::{account::DeleteAccount, error::AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+/// Deactivate a user of the workspace, it's signed out and can't sign in until reactivated.
+///
+/// - Admins can deactivate members and guests, the owner can deactivate admins too.
+/// - The SSE connections of the user are closed.
+/// - A user of other workspaces too can't be deactivated, remove it from the workspace instead.
+#[utoipa::path(
+    post,
+    path = "/api/users/{id}/deactivate",
+    params(
+        ("id" = i64, Path, description = "User id")
+    ),
+    responses(
+        (status = 204, description = "User deactivated"),
+        (status = 400, description = "User is already deactivated or a member of other workspaces", body = ErrorOutput),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn deactivate_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.deactivate_user(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reactivate a deactivated user of the workspace, a deleted account can't be reactivated.
+#[utoipa::path(
+    post,
+    path = "/api/users/{id}/reactivate",
+    params(
+        ("id" = i64, Path, description = "User id")
+    ),
+    responses(
+        (status = 204, description = "User reactivated"),
+        (status = 400, description = "User is not deactivated", body = ErrorOutput),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn reactivate_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reactivate_user(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Delete the account of the current user.
+///
+/// - The password is required if the user has one.
+/// - The messages are kept and shown as sent by "deleted user".
+/// - A workspace owner must transfer the ownership first, otherwise it will return 400.
+#[utoipa::path(
+    delete,
+    path = "/api/me",
+    request_body = DeleteAccount,
+    responses(
+        (status = 204, description = "Account deleted"),
+        (status = 400, description = "The user owns a workspace", body = ErrorOutput),
+        (status = 403, description = "Password is incorrect", body = ErrorOutput),
+        (status = 429, description = "Too many attempts", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn delete_account_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<DeleteAccount>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_account(&user, &input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Export the personal data of the current user as a zip archive.
+///
+/// - profile.json: the profile and the workspaces.
+/// - messages.json: the messages sent by the user.
+/// - files/: the files of the messages and the avatar.
+#[utoipa::path(
+    get,
+    path = "/api/me/export",
+    responses(
+        (status = 200, description = "Zip archive of the personal data", content_type = "application/zip"),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn export_personal_data_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let data = state.export_personal_data(&user).await?;
+    let headers = [
+        (header::CONTENT_TYPE, "application/zip".to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"user-{}-export.zip\"", user.id),
+        ),
+    ];
+    Ok((headers, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_router;
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn deactivated_user_token_should_be_rejected() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let admin = state.find_user_by_id(2).await?.unwrap();
+        let admin_token = state.ek.sign(admin)?;
+        let user = state.find_user_by_id(3).await?.unwrap();
+        let token = state.ek.sign(user)?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/users/3/deactivate")
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = Request::builder()
+            .uri("/api/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/users/3/reactivate")
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = Request::builder()
+            .uri("/api/me/export")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/zip");
+        Ok(())
+    }
+}
@@ -1,15 +1,59 @@
 use crate::{
+    email_verification::VerifyEmail,
     error::{AppError, ErrorOutput},
+    identity::OidcCallback,
+    password_reset::{RequestPasswordReset, ResetPassword},
+    session::SessionInfo,
+    totp::{SigninTotp, TotpCode},
     user::{CreateUser, SigninUser},
     AppState,
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    Extension, Json,
+};
+use chat_core::{Principal, User};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::warn;
 use utoipa::ToSchema;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthOutput {
+    /// short-lived access token
     pub token: String,
+    /// use it to get a new access token via /api/refresh
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+impl AppState {
+    /// start a session, sign an access token and create a refresh token of it for the user.
+    /// A deactivated user is refused.
+    pub(crate) async fn issue_tokens(
+        &self,
+        user: User,
+        info: &SessionInfo,
+    ) -> Result<AuthOutput, AppError> {
+        self.ensure_user_active(user.id).await?;
+        // users removed from their last workspace can't sign in until invited again
+        self.workspace_role(&user).await?;
+        let session_id = self.create_session(user.id, info).await?;
+        let refresh_token = self.create_refresh_token(user.id, Some(session_id)).await?;
+        let token = self
+            .ek
+            .sign(self.user_claims(user, Some(session_id)).await?)?;
+        Ok(AuthOutput {
+            token,
+            refresh_token,
+        })
+    }
 }
 
 #[utoipa::path(
@@ -18,6 +62,8 @@ pub struct AuthOutput {
     request_body = CreateUser,
     responses(
         (status=200, description="User created successfully", body=AuthOutput),
+        (status=400, description="Password is too short or too common", body=ErrorOutput),
+        (status=403, description="Invalid invite", body=ErrorOutput),
     ),
     tag = "chat"
 
@@ -25,41 +71,65 @@ pub struct AuthOutput {
 /// Create a new user in the chat system with email, password workspace and full name.
 ///
 /// - If the email already exists, it will return 409.
+/// - If the password is too short or too common, it will return 400.
 /// - Otherwise, it will return 201 with a token.
-/// - If the workspace doesn't exist, it will create one.
+/// - If the workspace doesn't exist, it will create one owned by the user, with a `general` channel.
+/// - Joining an existing workspace requires a valid invite code, otherwise it will return 403.
+/// - A verification token is mailed to the email address, if the mail fails it can be resent.
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    info: SessionInfo,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
+    let output = state.issue_tokens(user, &info).await?;
     // let mut header = HeaderMap::new();
     // header.insert("X-Token", token.parse()?);
     // Ok((StatusCode::CREATED, header))
-    let body = Json(AuthOutput { token });
+    let body = Json(output);
     Ok((StatusCode::CREATED, body))
 }
 
 /// Sign in a user with email and password.
+///
+/// - If the user has TOTP enabled, it returns 202 with a challenge, complete it via /api/signin/totp.
+/// - Failed signins back off exponentially per account, it returns 429 before the next attempt is allowed.
+/// - The account(423) or IP(429) is locked for a while after too many failures.
 #[utoipa::path(
     post,
     path = "/api/signin",
     request_body = SigninUser,
     responses(
-        (status=200, description="User Signin", body=Chat),
+        (status=200, description="User Signin", body=AuthOutput),
+        (status=202, description="Two-factor code required", body=MfaChallenge),
+        (status=423, description="Account is locked", body=ErrorOutput),
+        (status=429, description="Too many attempts", body=ErrorOutput),
     ),
     tag = "chat"
 
 )]
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    info: SessionInfo,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
+    let ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let email = input.email.clone();
+    // reserved before verifying the password, argon2 is expensive
+    state.reserve_signin_attempt(&email, ip).await?;
     let user = state.verify_user(input).await?;
+    if user.is_some() {
+        state.record_signin_success(&email, ip).await?;
+    }
     match user {
+        Some(user) if state.is_totp_enabled(user.id).await? => {
+            let challenge = state.create_mfa_challenge(user.id).await?;
+            Ok((StatusCode::ACCEPTED, Json(challenge)).into_response())
+        }
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            let output = state.issue_tokens(user, &info).await?;
+            Ok((StatusCode::OK, Json(output)).into_response())
         }
         None => Ok((
             StatusCode::FORBIDDEN,
@@ -69,18 +139,348 @@ pub(crate) async fn signin_handler(
     }
 }
 
+/// Complete the signin challenge with a TOTP code or a recovery code.
+///
+/// - A challenge expires in 5 minutes and allows 5 attempts.
+#[utoipa::path(
+    post,
+    path = "/api/signin/totp",
+    request_body = SigninTotp,
+    responses(
+        (status=200, description="User Signin", body=AuthOutput),
+        (status=400, description="Invalid or expired challenge", body=ErrorOutput),
+        (status=401, description="Invalid code", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn signin_totp_handler(
+    State(state): State<AppState>,
+    info: SessionInfo,
+    Json(input): Json<SigninTotp>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.complete_mfa_challenge(&input).await?;
+    let output = state.issue_tokens(user, &info).await?;
+    Ok(Json(output))
+}
+
+/// Sign in by the OpenID Connect identity provider, it redirects to the IdP login page.
+///
+/// - The IdP redirects back to /api/oidc/callback after the user signs in.
+#[utoipa::path(
+    get,
+    path = "/api/oidc/login",
+    responses(
+        (status=303, description="Redirect to the IdP login page"),
+        (status=404, description="OIDC is not configured", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn oidc_login_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let oidc = state.oidc()?;
+    let login = state.create_oidc_login().await?;
+    let url = oidc
+        .authorization_url(&login.state, &login.nonce, &login.code_verifier)
+        .await?;
+    Ok(Redirect::to(url.as_str()))
+}
+
+/// The IdP redirects back here with an authorization code, which is exchanged for the chat tokens.
+///
+/// - A user is created in the configured workspace when the IdP identity signs in for the first time.
+/// - If the user has TOTP enabled, it returns 202 with a challenge, complete it via /api/signin/totp.
+#[utoipa::path(
+    get,
+    path = "/api/oidc/callback",
+    params(OidcCallback),
+    responses(
+        (status=200, description="User Signin", body=AuthOutput),
+        (status=202, description="Two-factor code required", body=MfaChallenge),
+        (status=401, description="Invalid state, code or id_token", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn oidc_callback_handler(
+    State(state): State<AppState>,
+    info: SessionInfo,
+    Query(input): Query<OidcCallback>,
+) -> Result<impl IntoResponse, AppError> {
+    let oidc = state.oidc()?;
+    let login = state.take_oidc_login(&input.state).await?;
+    let code = match (input.code, input.error) {
+        (_, Some(error)) => return Err(AppError::OidcError(error)),
+        (Some(code), None) => code,
+        (None, None) => return Err(AppError::OidcError("missing code".to_string())),
+    };
+    let identity = oidc
+        .exchange_code(&code, &login.code_verifier, &login.nonce)
+        .await?;
+    let user = state
+        .find_or_create_oidc_user(&identity, &oidc.config().workspace)
+        .await?;
+    if state.is_totp_enabled(user.id).await? {
+        let challenge = state.create_mfa_challenge(user.id).await?;
+        return Ok((StatusCode::ACCEPTED, Json(challenge)).into_response());
+    }
+    let output = state.issue_tokens(user, &info).await?;
+    Ok((StatusCode::OK, Json(output)).into_response())
+}
+
+/// Exchange a refresh token for a new access token and refresh token.
+///
+/// - The refresh token can only be used once, a reused token revokes all the refresh tokens of the user.
+#[utoipa::path(
+    post,
+    path = "/api/refresh",
+    request_body = RefreshToken,
+    responses(
+        (status=200, description="Token refreshed", body=AuthOutput),
+        (status=401, description="Invalid refresh token", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, session_id, refresh_token) =
+        state.rotate_refresh_token(&input.refresh_token).await?;
+    let token = state.ek.sign(state.user_claims(user, session_id).await?)?;
+    Ok(Json(AuthOutput {
+        token,
+        refresh_token,
+    }))
+}
+
+/// Sign out, revoke the current session, access token and the given refresh token.
+#[utoipa::path(
+    post,
+    path = "/api/signout",
+    request_body = RefreshToken,
+    responses(
+        (status=204, description="User signed out"),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn signout_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .revoke_refresh_token(&input.refresh_token, user.id)
+        .await?;
+    if let Some(session_id) = principal.session_id {
+        state.revoke_session(session_id, user.id).await?;
+    }
+    if let (Some(jti), Some(expires_at)) = (principal.jwt_id, principal.expires_at) {
+        state.revoke_access_token(&jti, user.id, expires_at).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Request a password reset, a reset token is mailed to the user.
+///
+/// - It always returns 202 at once and mails the token in the background,
+///   so the caller can't tell whether the email is registered.
+#[utoipa::path(
+    post,
+    path = "/api/password/reset",
+    request_body = RequestPasswordReset,
+    responses(
+        (status=202, description="Reset token is mailed if the email exists"),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn request_password_reset_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RequestPasswordReset>,
+) -> impl IntoResponse {
+    // neither the status nor the response time tells whether the email is registered
+    tokio::spawn(async move {
+        if let Err(e) = state.request_password_reset(&input).await {
+            warn!("failed to request the password reset: {:?}", e);
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+/// Set a new password with the mailed reset token.
+///
+/// - The token can only be used once, and all the refresh tokens of the user are revoked.
+#[utoipa::path(
+    post,
+    path = "/api/password/reset/confirm",
+    request_body = ResetPassword,
+    responses(
+        (status=204, description="Password is reset"),
+        (status=400, description="Invalid or expired token, or weak password", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.reset_password(&input).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Verify the email address with the mailed token.
+///
+/// - The access token signed before still says unverified, use /api/refresh to get a new one.
+#[utoipa::path(
+    post,
+    path = "/api/email/verify",
+    request_body = VerifyEmail,
+    responses(
+        (status=200, description="Email verified", body=User),
+        (status=400, description="Invalid or expired token", body=ErrorOutput),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    Json(input): Json<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.verify_email(&input).await?;
+    Ok(Json(user))
+}
+
+/// Mail a new verification token to the current user, if the email is not verified yet.
+#[utoipa::path(
+    post,
+    path = "/api/email/verify/resend",
+    responses(
+        (status=202, description="Verification token is mailed"),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn resend_email_verification_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    // the token may be signed before the email is verified
+    let user = state
+        .find_user_by_id(user.id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} not found", user.id)))?;
+    if user.verified_at.is_none() {
+        state.send_email_verification(&user).await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Start TOTP enrollment, returns the secret and an otpauth uri for authenticator apps.
+///
+/// - TOTP is not enabled until a code is confirmed via /api/2fa/totp/confirm.
+#[utoipa::path(
+    post,
+    path = "/api/2fa/totp/enroll",
+    responses(
+        (status=200, description="TOTP secret generated", body=TotpEnrollment),
+        (status=400, description="TOTP is already enabled", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn enroll_totp_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.enroll_totp(&user).await?;
+    Ok(Json(enrollment))
+}
+
+/// Confirm TOTP enrollment with a code, returns the recovery codes which are only shown once.
+#[utoipa::path(
+    post,
+    path = "/api/2fa/totp/confirm",
+    request_body = TotpCode,
+    responses(
+        (status=200, description="TOTP enabled", body=RecoveryCodes),
+        (status=401, description="Invalid code", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn confirm_totp_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let codes = state.confirm_totp(&user, &input.code).await?;
+    Ok(Json(codes))
+}
+
+/// Disable TOTP with a TOTP code or a recovery code.
+#[utoipa::path(
+    post,
+    path = "/api/2fa/totp/disable",
+    request_body = TotpCode,
+    responses(
+        (status=204, description="TOTP disabled"),
+        (status=401, description="Invalid code", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn disable_totp_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    state.disable_totp(user.id, &input.code).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public keys used to verify the access tokens, as a JSON Web Key Set.
+///
+/// - Other services (e.g. notify_server) fetch it instead of embedding the public key.
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses(
+        (status=200, description="JSON Web Key Set", body=Jwks),
+    ),
+    tag = "chat"
+)]
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.dk.jwks())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        get_router,
+        handlers::unlock_user_handler,
+        mailer::MailerConfig,
+        oidc::{
+            mock::{MockIdentity, MockIdp},
+            OidcConfig,
+        },
+        totp::{MfaChallenge, RecoveryCodes, TotpEnrollment},
+    };
     use anyhow::Result;
+    use axum::{body::Body, extract::Path, http::Request};
+    use chat_core::{
+        middleware::TokenVerify,
+        utils::jwt::{DecodingKey, Jwks},
+    };
     use http_body_util::BodyExt;
     use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
 
     #[tokio::test]
     async fn test_signup_handler() -> Result<()> {
         let (_tgp, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("default", "aaa@hkjc.org.hk", "zackjchen", "hunter42");
-        let res = signup_handler(State(state), Json(input))
+        let input = CreateUser::new("acme", "aaa@hkjc.org.hk", "zackjchen", "hunter42");
+        let res = signup_handler(State(state.clone()), SessionInfo::default(), Json(input))
             .await?
             .into_response();
         assert_eq!(res.status(), StatusCode::CREATED);
@@ -89,19 +489,69 @@ mod tests {
         let bytes = body.collect().await?.to_bytes();
         let auth: AuthOutput = serde_json::from_slice(&bytes)?;
         assert_ne!(auth.token, "");
+
+        // signup sends the verification mail
+        let mails = state.read_mails("aaa@hkjc.org.hk").await;
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].contains("Verify your email address"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signup_should_not_fail_with_mailer() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test_with(|config| {
+            // the mail dir can't be created under a file
+            config.mailer = MailerConfig::File {
+                dir: "/dev/null/mails".into(),
+            }
+        })
+        .await?;
+        let input = CreateUser::new("acme", "aaa@hkjc.org.hk", "zackjchen", "hunter42");
+        let res = signup_handler(State(state.clone()), SessionInfo::default(), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let user = state.find_user_by_email("aaa@hkjc.org.hk").await?.unwrap();
+        let (tokens,): (i64,) = sqlx::query_as(
+            "select count(*) from email_verification_tokens where user_id = $1 and used_at is null",
+        )
+        .bind(user.id)
+        .fetch_one(&state.pool)
+        .await?;
+        assert_eq!(tokens, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_password_reset_should_not_tell_emails() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test_with(|config| {
+            config.mailer = MailerConfig::File {
+                dir: "/dev/null/mails".into(),
+            }
+        })
+        .await?;
+        for email in ["zack.j.chen@hkjc.org.hk", "nobody@hkjc.org.hk"] {
+            let input = RequestPasswordReset {
+                email: email.to_string(),
+            };
+            let res = request_password_reset_handler(State(state.clone()), Json(input))
+                .await
+                .into_response();
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+        }
         Ok(())
     }
 
     #[tokio::test]
     async fn test_duplicate_signup_handler_should_409() -> Result<()> {
         let (_tgp, state) = AppState::new_for_test().await?;
-        let input1 = CreateUser::new("default", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
-        let input2 = CreateUser::new("default", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
+        let input1 = CreateUser::new("acme", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
+        let input2 = CreateUser::new("acme", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
 
-        let _res1 = signup_handler(State(state.clone()), Json(input1))
+        let _res1 = signup_handler(State(state.clone()), SessionInfo::default(), Json(input1))
             .await?
             .into_response();
-        let res2 = signup_handler(State(state), Json(input2))
+        let res2 = signup_handler(State(state), SessionInfo::default(), Json(input2))
             .await
             .into_response();
         assert_eq!(res2.status(), StatusCode::CONFLICT);
@@ -121,13 +571,13 @@ mod tests {
     #[tokio::test]
     async fn test_signin_handler() -> Result<()> {
         let (_tgp, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("default", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
-        let _res1 = signup_handler(State(state.clone()), Json(input))
+        let input = CreateUser::new("acme", "zackjchen@hkjc.org.hk", "zackjchen", "hunter43");
+        let _res1 = signup_handler(State(state.clone()), SessionInfo::default(), Json(input))
             .await?
             .into_response();
 
         let signinuser = SigninUser::new("zackjchen@hkjc.org.hk", "hunter43");
-        let res2 = signin_handler(State(state), Json(signinuser))
+        let res2 = signin_handler(State(state), None, SessionInfo::default(), Json(signinuser))
             .await?
             .into_response();
         assert_eq!(res2.status(), StatusCode::OK);
@@ -143,7 +593,7 @@ mod tests {
     async fn test_signin_with_non_exists_should_403() -> Result<()> {
         let (_tgp, state) = AppState::new_for_test().await?;
         let input = SigninUser::new("zack.j.chen@hkjc.org.hk", "hunter42");
-        let res = signin_handler(State(state), Json(input))
+        let res = signin_handler(State(state), None, SessionInfo::default(), Json(input))
             .await
             .into_response();
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
@@ -159,10 +609,324 @@ mod tests {
         // }
         Ok(())
     }
+    #[tokio::test]
+    async fn test_signin_with_long_email_should_403() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let email = format!("{}@hkjc.org.hk", "a".repeat(100));
+        let input = SigninUser::new(&email, "hunter42");
+        let res = signin_handler(State(state), None, SessionInfo::default(), Json(input))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_signin_failures_should_be_throttled() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let addr: SocketAddr = "10.0.0.1:10000".parse()?;
+        let signin = |password: &str| {
+            let input = SigninUser::new("zack.j.chen@hkjc.org.hk", password);
+            signin_handler(
+                State(state.clone()),
+                Some(ConnectInfo(addr)),
+                SessionInfo::default(),
+                Json(input),
+            )
+        };
+        let res = signin("wrong").await.into_response();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // even the right password has to wait
+        let res = signin("Jiajia520").await.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "1");
+
+        let threshold = state.config.auth.signin_throttle.account_lockout_threshold;
+        for _ in 1..threshold {
+            sqlx::query("update signin_attempts set next_attempt_at = null")
+                .execute(&state.pool)
+                .await?;
+            state
+                .reserve_signin_attempt("zack.j.chen@hkjc.org.hk", Some(addr.ip()))
+                .await?;
+        }
+        let res = signin("Jiajia520").await.into_response();
+        assert_eq!(res.status(), StatusCode::LOCKED);
+
+        // the owner role is checked by the route layer, the handler checks the workspace
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let user = state
+            .find_user_by_email("zack.j.chen@hkjc.org.hk")
+            .await?
+            .unwrap();
+        let res = unlock_user_handler(Extension(owner), State(state.clone()), Path(user.id))
+            .await
+            .into_response();
+        // user 1 is in another workspace
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let other = state.find_user_by_id(2).await?.unwrap();
+        let res = unlock_user_handler(Extension(other), State(state.clone()), Path(user.id))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = signin("Jiajia520").await?.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_signout_handler() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let signinuser = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+        let res = signin_handler(
+            State(state.clone()),
+            None,
+            SessionInfo::default(),
+            Json(signinuser),
+        )
+        .await?
+        .into_response();
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&bytes)?;
+
+        let input = RefreshToken {
+            refresh_token: auth.refresh_token.clone(),
+        };
+        let res = refresh_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let refreshed: AuthOutput = serde_json::from_slice(&bytes)?;
+        assert_ne!(refreshed.refresh_token, auth.refresh_token);
+
+        // sign out revokes the session, the access token and the refresh token
+        let (user, principal) = state.verify_principal(&refreshed.token).await?;
+        assert!(principal.session_id.is_some());
+        let input = RefreshToken {
+            refresh_token: refreshed.refresh_token.clone(),
+        };
+        let res = signout_handler(
+            Extension(user),
+            State(state.clone()),
+            principal,
+            Json(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            state.verify(&refreshed.token).await,
+            Err(AppError::TokenRevoked)
+        ));
+
+        let input = RefreshToken {
+            refresh_token: refreshed.refresh_token,
+        };
+        let res = refresh_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signout_with_query_token() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let token = state.ek.sign(user.clone())?;
+        let refresh_token = state.create_refresh_token(user.id, None).await?;
+        let app = get_router(state.clone()).await?;
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("/api/signout?access_token={}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&RefreshToken {
+                refresh_token,
+            })?))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            state.verify(&token).await,
+            Err(AppError::TokenRevoked)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_jwks_handler() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let res = jwks_handler(State(state.clone())).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let jwks: Jwks = serde_json::from_slice(&bytes)?;
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, state.ek.key_id());
+
+        let user = User::new(1, "zack@email.com", "Zack", "");
+        let token = state.ek.sign(user)?;
+        assert!(DecodingKey::from_jwks(&jwks)?.verify(&token).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signin_with_totp_handler() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let user = state
+            .find_user_by_email("zack.j.chen@hkjc.org.hk")
+            .await?
+            .unwrap();
+        let res = enroll_totp_handler(Extension(user.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let enrollment: TotpEnrollment = serde_json::from_slice(&bytes)?;
+        let totp = totp_rs::TOTP::from_url(&enrollment.otpauth_uri)?;
+        let input = TotpCode {
+            code: totp.generate_current()?,
+        };
+        let res = confirm_totp_handler(Extension(user), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let codes: RecoveryCodes = serde_json::from_slice(&bytes)?;
+
+        // password only gives a challenge
+        let signinuser = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+        let res = signin_handler(
+            State(state.clone()),
+            None,
+            SessionInfo::default(),
+            Json(signinuser),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let challenge: MfaChallenge = serde_json::from_slice(&bytes)?;
+
+        let input = SigninTotp {
+            challenge: challenge.challenge.clone(),
+            code: "123456".to_string(),
+        };
+        let res = signin_totp_handler(State(state.clone()), SessionInfo::default(), Json(input))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let input = SigninTotp {
+            challenge: challenge.challenge,
+            code: codes.recovery_codes[0].clone(),
+        };
+        let res = signin_totp_handler(State(state.clone()), SessionInfo::default(), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&bytes)?;
+        assert_eq!(
+            state.verify(&auth.token).await?.email,
+            "zack.j.chen@hkjc.org.hk"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oidc_signin_with_mock_idp() -> Result<()> {
+        let idp = MockIdp::start("chat").await;
+        let issuer = idp.issuer.clone();
+        let (_tgp, state) = AppState::new_for_test_with(|config| {
+            config.oidc = Some(OidcConfig {
+                issuer,
+                client_id: "chat".to_string(),
+                client_secret: None,
+                redirect_uri: "http://localhost:6688/api/oidc/callback".to_string(),
+                workspace: "idp".to_string(),
+                scopes: vec!["openid".to_string(), "email".to_string()],
+            })
+        })
+        .await?;
+
+        let res = oidc_login_handler(State(state.clone()))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let location = res.headers()["location"].to_str()?.to_string();
+        assert!(location.starts_with(&format!("{}/authorize?", idp.issuer)));
+
+        let identity = MockIdentity {
+            subject: "alice".to_string(),
+            email: "alice@hkjc.org.hk".to_string(),
+            email_verified: true,
+            name: "Alice".to_string(),
+        };
+        let (code, oidc_state) = idp.authorize(&location, identity);
+        let input = OidcCallback {
+            code: Some(code.clone()),
+            state: oidc_state.clone(),
+            error: None,
+        };
+        let res = oidc_callback_handler(State(state.clone()), SessionInfo::default(), Query(input))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&bytes)?;
+        let user = state.verify(&auth.token).await?;
+        assert_eq!(user.email, "alice@hkjc.org.hk");
+        assert_eq!(user.fullname, "Alice");
+
+        // the state can't be replayed
+        let input = OidcCallback {
+            code: Some(code),
+            state: oidc_state,
+            error: None,
+        };
+        let res = oidc_callback_handler(State(state.clone()), SessionInfo::default(), Query(input))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // the second factor is required once TOTP is enabled
+        let enrollment = state.enroll_totp(&user).await?;
+        let totp = totp_rs::TOTP::from_url(&enrollment.otpauth_uri)?;
+        state.confirm_totp(&user, &totp.generate_current()?).await?;
+        let res = oidc_login_handler(State(state.clone()))
+            .await?
+            .into_response();
+        let location = res.headers()["location"].to_str()?.to_string();
+        let identity = MockIdentity {
+            subject: "alice".to_string(),
+            email: "alice@hkjc.org.hk".to_string(),
+            email_verified: true,
+            name: "Alice".to_string(),
+        };
+        let (code, oidc_state) = idp.authorize(&location, identity);
+        let input = OidcCallback {
+            code: Some(code),
+            state: oidc_state,
+            error: None,
+        };
+        let res = oidc_callback_handler(State(state), SessionInfo::default(), Query(input))
+            .await?
+            .into_response();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let _: MfaChallenge = serde_json::from_slice(&bytes)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login_without_config_should_404() -> Result<()> {
+        let (_tgp, state) = AppState::new_for_test().await?;
+        let res = oidc_login_handler(State(state)).await.into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_duplicate_user_should_failed() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("default", "abc@hkjc.org.hk", "zackjchen", "hunter43");
+        let input = CreateUser::new("acme", "abc@hkjc.org.hk", "zackjchen", "hunter43");
         state.create_user(&input).await?;
         let res2 = state.create_user(&input).await;
         match res2 {
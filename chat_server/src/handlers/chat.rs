@@ -1,17 +1,29 @@
-use crate::{chat::CreateChat, error::AppError, AppState};
+use crate::{
+    chat::{CreateChat, ListChats, UpdateChat},
+    error::AppError,
+    AppState,
+};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    Principal, User, WorkspaceAction,
+};
 use tracing::info;
 
-/// list all chat
+/// list the chats of the current user, newest first
+///
+/// - The archived ones are listed only with `archived=true`.
+/// - The public channels not joined are listed too with `public=true`.
+/// - Use the id of the last chat as `last_id` for the next page.
 #[utoipa::path(
     get,
     path = "/api/chat",
+    params(ListChats),
     responses(
         (status=200, description="List of chats", body=[Chat]),
     ),
@@ -21,20 +33,21 @@ use tracing::info;
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(opts): Query<ListChats>,
 ) -> Result<impl IntoResponse, AppError> {
     info!("user:{:?}", user);
-    let chats = state.fetch_chats_all(user.ws_id as u64).await?;
-
+    let chats = state.list_chats(&user, &opts).await?;
     Ok((StatusCode::OK, Json(chats)))
 }
 
-/// create a new chat
+/// create a new chat, guests can't create public channels
 #[utoipa::path(
     post,
     path = "/api/chat",
     request_body = CreateChat,
     responses(
         (status=200, description="List of chats", body=[Chat]),
+        (status=403, description="Permission denied", body=PermissionDenied),
     ),
     security(("token" = [])),
     tag = "chat"
@@ -42,23 +55,213 @@ pub(crate) async fn list_chat_handler(
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    principal: Principal,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.create_chat(input, user.ws_id as _).await?;
+    // a named public chat is a public channel
+    let action = WorkspaceAction::CreatePublicChannel;
+    if input.public && input.name.is_some() && !principal.role.can(action) {
+        return Err(PermissionDenied::new(Permission::Action(action)).into());
+    }
+    let chat = state.create_chat(input, user.ws_id as _, user.id).await?;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
-// TODO: update chat
-pub(crate) async fn update_chat_handler() -> impl IntoResponse {
-    ""
+/// update a chat: rename it, add or remove members
+///
+/// - Only the owner of the chat and admins can rename it or remove others, members can remove themselves.
+/// - A single chat becomes a group once a member is added, it can't be renamed.
+/// - A group with more than 8 members must have a name, a channel must always have one.
+/// - The added members must be in the workspace of the chat.
+#[utoipa::path(
+    patch,
+    path = "/api/chat/{id}",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    request_body = UpdateChat,
+    responses(
+        (status=200, description="Chat updated", body=Chat),
+        (status=400, description="Invalid name or members", body=ErrorOutput),
+        (status=403, description="Permission denied", body=PermissionDenied),
+        (status=404, description="Chat not found", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .update_chat(id as _, input, &user, principal.role)
+        .await?;
+    Ok((StatusCode::OK, Json(chat)))
 }
 
-// TODO: delete chat
-pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
-    ""
+/// delete a chat with its messages, only admins and the owner can do it
+///
+/// - The files no longer referenced by any message are removed.
+/// - The members receive a `ChatDeleted` event.
+#[utoipa::path(
+    delete,
+    path = "/api/chat/{id}",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    responses(
+        (status=204, description="Chat deleted"),
+        (status=403, description="Permission denied", body=PermissionDenied),
+        (status=404, description="Chat not found", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn delete_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .ensure_chat_owner_or(id, user.id, principal.role, WorkspaceAction::DeleteChat)
+        .await?;
+    state.delete_chat(id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-/// get a chat
+/// archive a chat, it's read-only and hidden from the default list until it's restored.
+/// Only admins and the owner can do it.
+#[utoipa::path(
+    post,
+    path = "/api/chat/{id}/archive",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    responses(
+        (status=200, description="Chat archived", body=Chat),
+        (status=400, description="Chat is archived already", body=ErrorOutput),
+        (status=403, description="Permission denied", body=PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn archive_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .ensure_chat_owner_or(id, user.id, principal.role, WorkspaceAction::DeleteChat)
+        .await?;
+    let chat = state.archive_chat(id as _, true).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+/// restore an archived chat, only admins and the owner can do it
+#[utoipa::path(
+    post,
+    path = "/api/chat/{id}/unarchive",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    responses(
+        (status=200, description="Chat restored", body=Chat),
+        (status=400, description="Chat is not archived", body=ErrorOutput),
+        (status=403, description="Permission denied", body=PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn unarchive_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .ensure_chat_owner_or(id, user.id, principal.role, WorkspaceAction::DeleteChat)
+        .await?;
+    let chat = state.archive_chat(id as _, false).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+/// list the public channels of the workspace with their member counts, joined or not
+#[utoipa::path(
+    get,
+    path = "/api/chat/channels",
+    responses(
+        (status=200, description="List of public channels", body=[PublicChannel]),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn list_public_channels_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = state
+        .fetch_public_channels(user.ws_id as _, user.id)
+        .await?;
+    Ok((StatusCode::OK, Json(channels)))
+}
+
+/// join a public channel, guests can't join channels by themselves
+///
+/// - The members, the current user included, receive an `AddToChat` event.
+#[utoipa::path(
+    post,
+    path = "/api/chat/{id}/join",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    responses(
+        (status=200, description="Channel joined", body=Chat),
+        (status=400, description="Already a member", body=ErrorOutput),
+        (status=403, description="Permission denied", body=PermissionDenied),
+        (status=404, description="Public channel not found", body=ErrorOutput),
+        (status=409, description="Channel is archived", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn join_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_chat(id as _, &user).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+/// leave a channel, the current user receives a `RemoveFromChat` event
+#[utoipa::path(
+    post,
+    path = "/api/chat/{id}/leave",
+    params(
+        ("id"=i64, Path, description="chat id"),
+    ),
+    responses(
+        (status=204, description="Channel left"),
+        (status=400, description="The chat is not a channel", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.leave_chat(id as _, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// get a chat, the current user must be a member of it unless it's a public channel
 #[utoipa::path(
     get,
     path = "/api/chat/{id}",
@@ -69,17 +272,177 @@ pub(crate) async fn delete_chat_handler() -> impl IntoResponse {
     ),
     responses(
         (status=200, description="chat details", body=Chat),
+        (status=404, description="Chat not found or not visible", body=ErrorOutput),
     ),
     security(("token" = [])),
     tag = "chat"
 )]
 pub(crate) async fn get_chat_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<i64>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.fetch_chat_by_id(id as _).await?;
+    let chat = state.fetch_visible_chat(id as _, &user).await?;
     match chat {
         Some(chat) => Ok((StatusCode::OK, Json(chat))),
         None => Err(AppError::NotFound(format!("chat with id {} not found", id))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_router;
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn non_members_should_join_public_channels() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'guest' where user_id = 6")
+            .execute(&state.pool)
+            .await?;
+        let token = state.ek.sign(state.find_user_by_id(2).await?.unwrap())?;
+        let guest_token = state.ek.sign(state.find_user_by_id(6).await?.unwrap())?;
+        let app = get_router(state).await?;
+
+        let req = |uri: &str, token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+        let res = app
+            .clone()
+            .oneshot(req("/api/chat/4/join", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .clone()
+            .oneshot(req("/api/chat/4/join", &guest_token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = app.oneshot(req("/api/chat/4/leave", &token)?).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_of_another_workspace_should_not_delete_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 2 is a plain member of chat 2 in workspace 2, and an admin of workspace 3
+        sqlx::query("insert into workspace_members (ws_id, user_id, role) values (3, 2, 'admin')")
+            .execute(&state.pool)
+            .await?;
+        let mut user = state.find_user_by_id(2).await?.unwrap();
+        user.ws_id = 3;
+        let token = state.ek.sign(user)?;
+        let app = get_router(state.clone()).await?;
+
+        let req = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(req("DELETE", "/api/chat/2")?).await?;
+        assert!(res.status().is_client_error());
+        let res = app
+            .oneshot(req("DELETE", "/api/chat/2/messages/2")?)
+            .await?;
+        assert!(res.status().is_client_error());
+        assert!(state.fetch_chat_by_id(2).await?.is_some());
+        let (count,): (i64,) = sqlx::query_as("select count(*) from messages where id = 2")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(count, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owners_and_admins_should_archive_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let app = get_router(state.clone()).await?;
+        let req = |user_id: i64, method: &str, uri: &str| {
+            let state = state.clone();
+            let (method, uri) = (method.to_string(), uri.to_string());
+            async move {
+                let token = state
+                    .ek
+                    .sign(state.find_user_by_id(user_id).await?.unwrap())?;
+                anyhow::Ok(
+                    Request::builder()
+                        .method(method.as_str())
+                        .uri(uri)
+                        .header("Authorization", format!("Bearer {}", token))
+                        .body(Body::empty())?,
+                )
+            }
+        };
+
+        // user 3 is a plain member of chat 2, user 2 is its owner
+        for (method, uri) in [
+            ("POST", "/api/chat/2/archive"),
+            ("POST", "/api/chat/2/unarchive"),
+            ("DELETE", "/api/chat/2"),
+        ] {
+            let res = app.clone().oneshot(req(3, method, uri).await?).await?;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(state.ensure_chat_writable(2).await.is_ok());
+
+        let res = app
+            .clone()
+            .oneshot(req(2, "POST", "/api/chat/2/archive").await?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .clone()
+            .oneshot(req(2, "POST", "/api/chat/2/unarchive").await?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the role is checked per request, the token is kept
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 3")
+            .execute(&state.pool)
+            .await?;
+        let res = app
+            .clone()
+            .oneshot(req(3, "POST", "/api/chat/2/archive").await?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.oneshot(req(2, "DELETE", "/api/chat/2").await?).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_chat_should_not_leak_private_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.ek.sign(state.find_user_by_id(6).await?.unwrap())?;
+        let app = get_router(state).await?;
+        let get = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(get("/api/chat/2")?).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = app.clone().oneshot(get("/api/chat/4")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .oneshot(get("/api/chat?public=true&type=public_channel")?)
+            .await?;
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let chats: Vec<chat_core::Chat> = serde_json::from_slice(&bytes)?;
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].id, 4);
+        Ok(())
+    }
+}
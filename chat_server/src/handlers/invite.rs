@@ -0,0 +1,132 @@
+use crate::{error::AppError, invite::CreateInvite, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+/// Invite people to the workspace, only admins and the owner can do it.
+///
+/// - The code is only returned once, sign up with it to join the workspace.
+/// - An invite bound to an email is mailed to it, and only that email can use it.
+#[utoipa::path(
+    post,
+    path = "/api/invites",
+    request_body = CreateInvite,
+    responses(
+        (status=201, description="Invite created", body=CreatedInvite),
+        (status=400, description="Invalid max uses or expiry", body=ErrorOutput),
+        (status=403, description="Permission denied", body=PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let created = state.create_invite(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// List the pending invites of the workspace, which are not revoked, expired or used up.
+#[utoipa::path(
+    get,
+    path = "/api/invites",
+    responses(
+        (status=200, description="List of invites", body=Vec<Invite>),
+        (status=403, description="Permission denied", body=PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn list_invites_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let invites = state.list_invites(user.ws_id).await?;
+    Ok(Json(invites))
+}
+
+/// Revoke an invite, it can't be used to sign up anymore.
+#[utoipa::path(
+    delete,
+    path = "/api/invites/{id}",
+    params(
+        ("id" = i64, Path, description = "Invite id")
+    ),
+    responses(
+        (status=204, description="Invite revoked"),
+        (status=403, description="Permission denied", body=PermissionDenied),
+        (status=404, description="Invite not found", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn revoke_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_invite(id, user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, invite::CreatedInvite};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn only_owner_should_manage_invites() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let member = state.find_user_by_id(2).await?.unwrap();
+        let owner_token = state.ek.sign(state.user_claims(owner, None).await?)?;
+        let member_token = state.ek.sign(state.user_claims(member, None).await?)?;
+        let app = get_router(state).await?;
+        let create = |token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/invites")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"max_uses":5}"#))
+        };
+
+        let res = app.clone().oneshot(create(&member_token)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = app.clone().oneshot(create(&owner_token)?).await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let created: CreatedInvite = serde_json::from_slice(&bytes)?;
+        assert_eq!(created.invite.max_uses, Some(5));
+
+        let req = Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/invites/{}", created.invite.id))
+            .header("Authorization", format!("Bearer {}", owner_token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = Request::builder()
+            .uri("/api/invites")
+            .header("Authorization", format!("Bearer {}", owner_token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let invites: Vec<serde_json::Value> = serde_json::from_slice(&bytes)?;
+        assert!(invites.is_empty());
+        Ok(())
+    }
+}
@@ -9,7 +9,7 @@ use axum::{
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{Principal, User};
 use tokio::fs::{self};
 use tracing::{info, warn};
 
@@ -38,6 +38,59 @@ pub(crate) async fn send_message_handler(
     Ok(Json(msg))
 }
 
+/// Delete a message, the sender can delete its own messages, admins can delete others' too.
+#[utoipa::path(
+    delete,
+    path = "/api/chat/{id}/messages/{message_id}",
+    params(
+        ("id"=i64, Path, description="Chat Id"),
+        ("message_id"=i64, Path, description="Message Id"),
+    ),
+    responses(
+        (status = 204, description = "Message deleted"),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "Message not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn delete_message_handler(
+    State(state): State<AppState>,
+    Path((chat_id, message_id)): Path<(i64, i64)>,
+    Extension(user): Extension<User>,
+    principal: Principal,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .delete_message(chat_id, message_id, &user, principal.role)
+        .await?;
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+/// Tell the other members of the chat that the current user is typing.
+///
+/// - Call it repeatedly while typing, signals within 3 seconds are coalesced.
+/// - The members receive a `Typing` event, and another one with `typing: false` once it expires.
+#[utoipa::path(
+    post,
+    path = "/api/chat/{id}/typing",
+    params(
+        ("id"=i64, Path, description="Chat Id"),
+    ),
+    responses(
+        (status = 204, description = "Typing signaled"),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn typing_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    state.signal_typing(chat_id, user.id).await?;
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
 /// list all messages of chat
 #[utoipa::path(
     get,
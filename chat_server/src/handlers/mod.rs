@@ -1,11 +1,21 @@
 use axum::response::IntoResponse;
+pub mod account;
 pub mod auth;
 pub mod chat;
+pub mod invite;
 pub mod messages;
+pub mod personal_access_token;
+pub mod profile;
+pub mod session;
 pub mod workspace;
+pub(crate) use account::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use invite::*;
 pub(crate) use messages::*;
+pub(crate) use personal_access_token::*;
+pub(crate) use profile::*;
+pub(crate) use session::*;
 pub(crate) use workspace::*;
 pub(crate) async fn index_handler() -> impl IntoResponse {
     todo!()
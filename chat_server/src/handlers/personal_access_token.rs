@@ -0,0 +1,134 @@
+use crate::{error::AppError, personal_access_token::CreatePersonalAccessToken, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+/// Create a personal access token limited to the scopes, e.g. for automation.
+///
+/// - The token is only returned once, use it as the bearer token.
+/// - A personal access token can't manage tokens itself.
+#[utoipa::path(
+    post,
+    path = "/api/tokens",
+    request_body = CreatePersonalAccessToken,
+    responses(
+        (status=201, description="Token created", body=CreatedPersonalAccessToken),
+        (status=400, description="Invalid name, scopes or expiration", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn create_personal_access_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreatePersonalAccessToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let created = state.create_personal_access_token(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// List the personal access tokens of the current user which are not revoked.
+#[utoipa::path(
+    get,
+    path = "/api/tokens",
+    responses(
+        (status=200, description="List of tokens", body=Vec<PersonalAccessToken>),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn list_personal_access_tokens_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = state.list_personal_access_tokens(user.id).await?;
+    Ok(Json(tokens))
+}
+
+/// Revoke a personal access token, it's rejected immediately.
+#[utoipa::path(
+    delete,
+    path = "/api/tokens/{id}",
+    params(
+        ("id" = i64, Path, description = "Token id")
+    ),
+    responses(
+        (status=204, description="Token revoked"),
+        (status=404, description="Token not found", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn revoke_personal_access_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_personal_access_token(id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_router;
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use chat_core::{
+        middleware::permission::{Permission, PermissionDenied},
+        Scope,
+    };
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn personal_access_token_should_be_limited_to_scopes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreatePersonalAccessToken {
+            name: "reader".to_string(),
+            scopes: vec![Scope::MessagesRead],
+            expires_in_days: None,
+        };
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let token = state
+            .create_personal_access_token(&user, &input)
+            .await?
+            .token;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .uri("/api/chat/2/messages?limit=10")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/chat/2/messages")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"content":"hello","files":[]}"#))?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let denied: PermissionDenied = serde_json::from_slice(&bytes)?;
+        assert_eq!(denied.required, Permission::Scope(Scope::MessagesWrite));
+
+        // a personal access token can't mint new tokens
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/tokens")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"name":"x","scopes":["messages:write"]}"#))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{
+    error::AppError,
+    profile::{ChangePassword, UpdateProfile},
+    AppState,
+};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::{Principal, User};
+
+/// Get the profile of the current user.
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    responses(
+        (status = 200, description = "Profile of the current user", body = Profile),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn get_profile_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = state.fetch_profile(&user).await?;
+    Ok(Json(profile))
+}
+
+/// Update the profile of the current user, the users sharing a workspace are notified.
+///
+/// - Upload the avatar image via /api/upload first, then set its url.
+/// - An empty string clears the title, timezone or avatar.
+#[utoipa::path(
+    patch,
+    path = "/api/me",
+    request_body = UpdateProfile,
+    responses(
+        (status = 200, description = "Profile updated", body = Profile),
+        (status = 400, description = "Invalid input", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn update_profile_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateProfile>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = state.update_profile(&user, &input).await?;
+    Ok(Json(profile))
+}
+
+/// Change the password with the current one, the other sessions are signed out.
+#[utoipa::path(
+    post,
+    path = "/api/me/password",
+    request_body = ChangePassword,
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 400, description = "New password is too short or too common", body = ErrorOutput),
+        (status = 403, description = "Current password is incorrect", body = ErrorOutput),
+        (status = 429, description = "Too many attempts", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn change_password_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Json(input): Json<ChangePassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .change_password(&user, principal.session_id, &input)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, profile::Profile};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn profile_should_be_editable() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let token = state.ek.sign(user)?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .method("PATCH")
+            .uri("/api/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"fullname":"Zack C","title":"engineer"}"#))?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .uri("/api/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let profile: Profile = serde_json::from_slice(&bytes)?;
+        assert_eq!(profile.fullname, "Zack C");
+        assert_eq!(profile.title.as_deref(), Some("engineer"));
+
+        let req = Request::builder()
+            .method("PATCH")
+            .uri("/api/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"fullname":"  "}"#))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{error::AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Principal, User};
+
+/// List the signed in sessions(devices) of the current user, the current one is flagged.
+#[utoipa::path(
+    get,
+    path = "/api/sessions",
+    responses(
+        (status=200, description="List of sessions", body=Vec<Session>),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn list_sessions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+) -> Result<impl IntoResponse, AppError> {
+    let sessions = state.list_sessions(user.id, principal.session_id).await?;
+    Ok(Json(sessions))
+}
+
+/// Revoke a session, its tokens are rejected and its SSE connections are closed immediately.
+#[utoipa::path(
+    delete,
+    path = "/api/sessions/{id}",
+    params(
+        ("id" = i64, Path, description = "Session id")
+    ),
+    responses(
+        (status=204, description="Session revoked"),
+        (status=404, description="Session not found", body=ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn revoke_session_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.revoke_session(id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, session::Session, user::SigninUser, AuthOutput};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn revoked_session_token_should_be_rejected() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let app = get_router(state).await?;
+        let signin = |device: &'static str| {
+            let app = app.clone();
+            async move {
+                let input = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+                let req = Request::builder()
+                    .method("POST")
+                    .uri("/api/signin")
+                    .header("Content-Type", "application/json")
+                    .header("X-Device-Name", device)
+                    .body(Body::from(serde_json::to_string(&input)?))?;
+                let bytes = app.oneshot(req).await?.into_body().collect().await?;
+                let auth: AuthOutput = serde_json::from_slice(&bytes.to_bytes())?;
+                anyhow::Ok(auth.token)
+            }
+        };
+        let laptop = signin("laptop").await?;
+        let phone = signin("phone").await?;
+
+        let req = Request::builder()
+            .uri("/api/sessions")
+            .header("Authorization", format!("Bearer {}", laptop))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let sessions: Vec<Session> = serde_json::from_slice(&bytes)?;
+        assert_eq!(sessions.len(), 2);
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(current.device_name.as_deref(), Some("laptop"));
+        let other = sessions.iter().find(|s| !s.current).unwrap();
+
+        let req = Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/sessions/{}", other.id))
+            .header("Authorization", format!("Bearer {}", laptop))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = Request::builder()
+            .uri("/api/sessions")
+            .header("Authorization", format!("Bearer {}", phone))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
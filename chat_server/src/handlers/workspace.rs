@@ -1,13 +1,31 @@
-use crate::{error::AppError, AppState};
-use axum::{extract::State, response::IntoResponse, Extension, Json};
-use chat_core::User;
+use crate::{
+    error::AppError,
+    workspace::{JoinWorkspace, TransferOwnership, UpdateUserRole, UpdateWorkspace, UserWorkspace},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Principal, User};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-/// list all users under workspace
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SwitchWorkspaceOutput {
+    /// access token of the workspace, the refresh token of the session keeps working in it
+    pub token: String,
+    pub workspace: UserWorkspace,
+}
+
+/// list all users under workspace, with their roles
 #[utoipa::path(
     get,
     path = "/api/users",
     responses(
-        (status = 200, description = "List of chat users", body = [User]),
+        (status = 200, description = "List of chat users", body = [ChatUser]),
     ),
     security(("token" = [])),
     tag = "chat"
@@ -17,7 +35,429 @@ pub(crate) async fn list_workspace_users_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    let users = state.fetch_workspace_all_users(user.id as u64).await?;
+    let users = state.fetch_workspace_all_users(user.ws_id as u64).await?;
 
     Ok(Json(users))
 }
+
+/// Change the role of a user in the workspace.
+///
+/// - Admins can manage members and guests, the owner can manage admins too.
+/// - The owner can't be changed here, transfer the ownership instead.
+#[utoipa::path(
+    patch,
+    path = "/api/users/{id}/role",
+    params(
+        ("id" = i64, Path, description = "User id")
+    ),
+    request_body = UpdateUserRole,
+    responses(
+        (status = 200, description = "Role updated", body = ChatUser),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn update_user_role_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(input): Json<UpdateUserRole>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.update_user_role(&user, id, input.role).await?;
+    Ok(Json(user))
+}
+
+/// List the workspaces of the current user, the active one of the token is flagged.
+#[utoipa::path(
+    get,
+    path = "/api/workspaces",
+    responses(
+        (status = 200, description = "List of workspaces", body = [UserWorkspace]),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.list_user_workspaces(user.id, user.ws_id).await?;
+    Ok(Json(workspaces))
+}
+
+/// Join another workspace with an invite, switch to it to work there.
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/join",
+    request_body = JoinWorkspace,
+    responses(
+        (status = 201, description = "Workspace joined", body = UserWorkspace),
+        (status = 400, description = "Already a member", body = ErrorOutput),
+        (status = 403, description = "Invalid invite", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn join_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<JoinWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.join_workspace(&user, &input).await?;
+    Ok((StatusCode::CREATED, Json(ws)))
+}
+
+/// Switch the active workspace of the session, it returns an access token of the workspace.
+///
+/// - The workspace is also the default one of the next signin.
+#[utoipa::path(
+    post,
+    path = "/api/workspaces/{id}/switch",
+    params(
+        ("id" = i64, Path, description = "Workspace id")
+    ),
+    responses(
+        (status = 200, description = "Workspace switched", body = SwitchWorkspaceOutput),
+        (status = 404, description = "Not a member of the workspace", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn switch_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let (user, workspace) = state
+        .switch_workspace(&user, id, principal.session_id)
+        .await?;
+    let claims = state.user_claims(user, principal.session_id).await?;
+    let token = state.ek.sign(claims)?;
+    Ok(Json(SwitchWorkspaceOutput { token, workspace }))
+}
+
+/// Get the details of the active workspace, only admins and the owner can do it.
+#[utoipa::path(
+    get,
+    path = "/api/workspace",
+    responses(
+        (status = 200, description = "Workspace details", body = WorkspaceDetails),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn get_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.fetch_workspace_details(user.ws_id).await?;
+    Ok(Json(ws))
+}
+
+/// Rename the active workspace, only admins and the owner can do it.
+#[utoipa::path(
+    patch,
+    path = "/api/workspace",
+    request_body = UpdateWorkspace,
+    responses(
+        (status = 200, description = "Workspace renamed", body = WorkspaceDetails),
+        (status = 400, description = "Invalid name", body = ErrorOutput),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 409, description = "Name is taken", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.rename_workspace(user.ws_id, &input.name).await?;
+    Ok(Json(ws))
+}
+
+/// Delete the active workspace with its chats, messages and files, only the owner can do it.
+#[utoipa::path(
+    delete,
+    path = "/api/workspace",
+    responses(
+        (status = 204, description = "Workspace deleted"),
+        (status = 400, description = "The default workspace can't be deleted", body = ErrorOutput),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn delete_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_workspace(user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Transfer the ownership of the active workspace to another member, the owner becomes an admin.
+#[utoipa::path(
+    post,
+    path = "/api/workspace/owner",
+    request_body = TransferOwnership,
+    responses(
+        (status = 200, description = "Ownership transferred", body = WorkspaceDetails),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn transfer_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TransferOwnership>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state
+        .transfer_workspace_ownership(&user, input.user_id)
+        .await?;
+    Ok(Json(ws))
+}
+
+/// Remove a user from the workspace and its chats.
+///
+/// - Admins can remove members and guests, the owner can remove admins too.
+#[utoipa::path(
+    delete,
+    path = "/api/users/{id}",
+    params(
+        ("id" = i64, Path, description = "User id")
+    ),
+    responses(
+        (status = 204, description = "User removed"),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn remove_workspace_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.remove_workspace_member(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Unlock a user locked by failed signins, only the workspace owner can do it.
+#[utoipa::path(
+    post,
+    path = "/api/users/{id}/unlock",
+    params(
+        ("id" = i64, Path, description = "User id")
+    ),
+    responses(
+        (status = 204, description = "User unlocked"),
+        (status = 403, description = "Permission denied", body = PermissionDenied),
+        (status = 404, description = "User not found", body = ErrorOutput),
+    ),
+    security(("token" = [])),
+    tag = "chat"
+)]
+pub(crate) async fn unlock_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let target = state
+        .find_user_by_id(id)
+        .await?
+        .filter(|u| u.ws_id == user.ws_id)
+        .ok_or_else(|| AppError::NotFound(format!("user {} not found", id)))?;
+    state.reset_signin_failures(&target.email).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, user::SigninUser, AuthOutput, ChatUser, RefreshToken};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use chat_core::{Chat, WorkspaceRole};
+    use http_body_util::BodyExt;
+    use jwt_simple::reexports::serde_json;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn guest_should_not_create_public_channel() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let admin = state.find_user_by_id(2).await?.unwrap();
+        let guest = state.find_user_by_id(3).await?.unwrap();
+        let admin_token = state.ek.sign(admin)?;
+        let guest_token = state.ek.sign(guest)?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .method("PATCH")
+            .uri("/api/users/3/role")
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"role":"guest"}"#))?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .uri("/api/users")
+            .header("Authorization", format!("Bearer {}", guest_token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        let bytes = res.into_body().collect().await?.to_bytes();
+        let users: Vec<ChatUser> = serde_json::from_slice(&bytes)?;
+        let role = |id| users.iter().find(|u| u.id == id).unwrap().role;
+        assert_eq!(role(2), WorkspaceRole::Admin);
+        assert_eq!(role(3), WorkspaceRole::Guest);
+        assert_eq!(role(4), WorkspaceRole::Member);
+
+        // the token is signed before the role is changed, but the current role applies
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/chat")
+            .header("Authorization", format!("Bearer {}", guest_token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                r#"{"name":"guests","members":[3,4],"public":true}"#,
+            ))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switch_workspace_should_scope_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("insert into workspace_members (ws_id, user_id) values (3, 6)")
+            .execute(&state.pool)
+            .await?;
+        let app = get_router(state).await?;
+        let send = |method: &str, uri: &str, token: &str, body: Option<String>| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(body.map(Body::from).unwrap_or_else(Body::empty));
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req?).await?;
+                let status = res.status();
+                let bytes = res.into_body().collect().await?.to_bytes();
+                anyhow::Ok((status, bytes))
+            }
+        };
+
+        let input = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+        let (_, bytes) = send(
+            "POST",
+            "/api/signin",
+            "",
+            Some(serde_json::to_string(&input)?),
+        )
+        .await?;
+        let auth: AuthOutput = serde_json::from_slice(&bytes)?;
+
+        let (_, bytes) = send("GET", "/api/workspaces", &auth.token, None).await?;
+        let workspaces: Vec<UserWorkspace> = serde_json::from_slice(&bytes)?;
+        assert_eq!(workspaces.len(), 2);
+        assert!(workspaces.iter().any(|ws| ws.id == 2 && ws.current));
+
+        let (status, bytes) = send("POST", "/api/workspaces/3/switch", &auth.token, None).await?;
+        assert_eq!(status, StatusCode::OK);
+        let switched: SwitchWorkspaceOutput = serde_json::from_slice(&bytes)?;
+        assert_eq!(switched.workspace.id, 3);
+        let (_, bytes) = send("GET", "/api/chat", &switched.token, None).await?;
+        let chats: Vec<Chat> = serde_json::from_slice(&bytes)?;
+        assert!(chats.is_empty());
+        // the old token is still scoped to its workspace
+        let (_, bytes) = send("GET", "/api/chat", &auth.token, None).await?;
+        let chats: Vec<Chat> = serde_json::from_slice(&bytes)?;
+        assert!(chats.iter().all(|chat| chat.ws_id == 2));
+
+        // the session stays in the workspace after refreshing
+        let input = RefreshToken {
+            refresh_token: auth.refresh_token,
+        };
+        let (_, bytes) = send(
+            "POST",
+            "/api/refresh",
+            "",
+            Some(serde_json::to_string(&input)?),
+        )
+        .await?;
+        let auth: AuthOutput = serde_json::from_slice(&bytes)?;
+        let (_, bytes) = send("GET", "/api/workspaces", &auth.token, None).await?;
+        let workspaces: Vec<UserWorkspace> = serde_json::from_slice(&bytes)?;
+        assert!(workspaces.iter().any(|ws| ws.id == 3 && ws.current));
+
+        let (status, _) = send("POST", "/api/workspaces/4/switch", &auth.token, None).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_admin_api_should_need_roles() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let admin = state.find_user_by_id(2).await?.unwrap();
+        let member = state.find_user_by_id(3).await?.unwrap();
+        let admin_token = state.ek.sign(admin)?;
+        let member_token = state.ek.sign(member)?;
+        let app = get_router(state).await?;
+        let send = |method: &str, uri: &str, token: &str, body: &'static str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body));
+            let app = app.clone();
+            async move { anyhow::Ok(app.oneshot(req?).await?.status()) }
+        };
+
+        let status = send("GET", "/api/workspace", &member_token, "").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = send(
+            "PATCH",
+            "/api/workspace",
+            &admin_token,
+            r#"{"name":"acme"}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        let status = send("DELETE", "/api/workspace", &admin_token, "").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = send(
+            "POST",
+            "/api/workspace/owner",
+            &admin_token,
+            r#"{"user_id":2}"#,
+        )
+        .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = send("DELETE", "/api/users/2", &member_token, "").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = send("DELETE", "/api/users/3", &admin_token, "").await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        // the removed user can't use its token in the workspace
+        let status = send("GET", "/api/chat", &member_token, "").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::{future::Future, path::PathBuf, pin::Pin, sync::Arc};
+use tokio::fs;
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// send mails to users, e.g. password reset and email verification
+pub trait Mailer: Send + Sync {
+    fn send<'a>(
+        &'a self,
+        mail: &'a Mail,
+    ) -> Pin<Box<dyn Future<Output = Result<(), AppError>> + Send + 'a>>;
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MailerConfig {
+    /// only log the mails, useful for local development
+    #[default]
+    Log,
+    /// write every mail as a file under `dir`
+    File { dir: PathBuf },
+}
+
+impl MailerConfig {
+    pub fn build(&self) -> Arc<dyn Mailer> {
+        match self {
+            MailerConfig::Log => Arc::new(LogMailer),
+            MailerConfig::File { dir } => Arc::new(FileMailer { dir: dir.clone() }),
+        }
+    }
+}
+
+pub struct LogMailer;
+
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl Mail {
+    pub fn new(to: impl Into<String>, subject: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            to: to.into(),
+            subject: subject.into(),
+            body: body.into(),
+        }
+    }
+
+    fn to_eml(&self) -> String {
+        format!(
+            "To: {}\r\nSubject: {}\r\n\r\n{}",
+            self.to, self.subject, self.body
+        )
+    }
+}
+
+impl Mailer for LogMailer {
+    fn send<'a>(
+        &'a self,
+        mail: &'a Mail,
+    ) -> Pin<Box<dyn Future<Output = Result<(), AppError>> + Send + 'a>> {
+        Box::pin(async move {
+            info!("Send mail: {:?}", mail);
+            Ok(())
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(
+        &'a self,
+        mail: &'a Mail,
+    ) -> Pin<Box<dyn Future<Output = Result<(), AppError>> + Send + 'a>> {
+        Box::pin(async move {
+            fs::create_dir_all(&self.dir).await?;
+            let path = self.dir.join(format!("{}.eml", uuid::Uuid::now_v7()));
+            fs::write(&path, mail.to_eml()).await?;
+            info!("Write mail to {:?}: {}", path, mail.subject);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_mailer_should_work() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(uuid::Uuid::now_v7().to_string());
+        let mailer = MailerConfig::File { dir: dir.clone() }.build();
+        let mail = Mail::new("zack@email.com", "hello", "world");
+        mailer.send(&mail).await?;
+
+        let mut entries = fs::read_dir(&dir).await?;
+        let entry = entries.next_entry().await?.expect("mail should exist");
+        let content = fs::read_to_string(entry.path()).await?;
+        assert_eq!(content, "To: zack@email.com\r\nSubject: hello\r\n\r\nworld");
+        fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
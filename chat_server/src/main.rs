@@ -1,5 +1,6 @@
 use anyhow::Result;
 use chat_server::{get_router, AppConfig, AppState};
+use std::net::SocketAddr;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::{
     fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
@@ -16,6 +17,11 @@ async fn main() -> Result<()> {
     let state = AppState::try_new(config).await?;
     let app = get_router(state).await?;
 
-    axum::serve(listener, app.into_make_service()).await?;
+    // the client address is used to throttle failed signins
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
@@ -9,12 +9,15 @@ use chat_core::User;
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
+    // the chat id is the first path param, e.g. /:id/messages/:message_id
+    let Path(params) = Path::<Vec<(String, u64)>>::from_request_parts(&mut parts, &state)
         .await
         .unwrap();
+    let chat_id = params.first().map(|(_, id)| *id).unwrap_or_default();
     let user = parts.extensions.get::<User>().unwrap();
+    // the role of the token only applies to the chats of its workspace
     if !state
-        .is_chat_member(chat_id as i64, user.id as u64)
+        .is_workspace_chat_member(chat_id as i64, user)
         .await
         .unwrap()
     {
@@ -45,7 +48,9 @@ mod tests {
     #[tokio::test]
     async fn test_verify_token_middleware_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let user = User::new(1, "test.chat@test.com", "test_chat", "123asd");
+        let mut user = User::new(1, "test.chat@test.com", "test_chat", "123asd");
+        // the token is rejected unless the user is a member of its workspace
+        user.ws_id = 1;
         let token = state.ek.sign(user.clone())?;
         let app = Router::new()
             .route("/test/:id", get(handler))
@@ -0,0 +1,73 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::AppError, AppState, EmailVerification};
+use chat_core::User;
+
+/// reject unverified users if `auth.email_verification` is `all`
+pub async fn require_verified_email(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let required = state.config.auth.email_verification == EmailVerification::All;
+    check_verified(required, req, next).await
+}
+
+/// reject unverified users from sending messages unless `auth.email_verification` is `off`
+pub async fn require_verified_email_to_send(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let required = state.config.auth.email_verification != EmailVerification::Off;
+    check_verified(required, req, next).await
+}
+
+async fn check_verified(required: bool, req: Request, next: Next) -> Response {
+    if required {
+        let verified = req
+            .extensions()
+            .get::<User>()
+            .is_some_and(|user| user.verified_at.is_some());
+        if !verified {
+            return AppError::EmailNotVerified.into_response();
+        }
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{body::Body, http::StatusCode, middleware::from_fn, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "Ok")
+    }
+
+    #[tokio::test]
+    async fn check_verified_should_work() -> Result<()> {
+        let mut user = User::new(1, "test@email.com", "test", "");
+        let app = Router::new()
+            .route("/test", get(handler))
+            .layer(from_fn(|req, next| check_verified(true, req, next)));
+
+        let mut req = Request::builder().uri("/test").body(Body::empty())?;
+        req.extensions_mut().insert(user.clone());
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        user.verified_at = Some(chrono::Utc::now());
+        let mut req = Request::builder().uri("/test").body(Body::empty())?;
+        req.extensions_mut().insert(user);
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
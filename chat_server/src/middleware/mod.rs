@@ -1,2 +1,4 @@
 pub mod chat;
+pub mod email;
 pub use chat::verify_chat;
+pub use email::{require_verified_email, require_verified_email_to_send};
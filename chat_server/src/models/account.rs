@@ -0,0 +1,359 @@
+use crate::{error::AppError, profile::Profile, workspace::UserWorkspace, AppState, ChatFile};
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    Message, User, WorkspaceAction,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use std::{collections::BTreeSet, io::Write, str::FromStr};
+use tokio::fs;
+use utoipa::ToSchema;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+/// fullname of a deleted user, its messages are kept
+pub const DELETED_USER_NAME: &str = "deleted user";
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct DeleteAccount {
+    /// required if the user has a password
+    pub password: Option<String>,
+}
+
+/// profile.json of the personal data export
+#[derive(Debug, Serialize)]
+struct ExportedProfile {
+    #[serde(flatten)]
+    profile: Profile,
+    workspaces: Vec<UserWorkspace>,
+}
+
+impl AppState {
+    /// a deactivated(or deleted) user can't sign in and its tokens are rejected
+    pub async fn ensure_user_active(&self, user_id: i64) -> Result<(), AppError> {
+        let rec: Option<(bool,)> =
+            sqlx::query_as("select deactivated_at is null from users where id = $1")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        match rec {
+            Some((true,)) => Ok(()),
+            Some((false,)) => Err(AppError::AccountDeactivated),
+            None => Err(AppError::NotFound(format!("user {} not found", user_id))),
+        }
+    }
+
+    /// deactivate a user with a lower role in the workspace of the actor,
+    /// its sessions are revoked and notify_server closes its SSE connections.
+    /// A user of other workspaces too can't be deactivated, it would be locked out of them.
+    pub async fn deactivate_user(&self, actor: &User, user_id: i64) -> Result<(), AppError> {
+        self.check_outranks(actor, user_id).await?;
+        let (elsewhere,): (bool,) = sqlx::query_as(
+            "select exists(select 1 from workspace_members where user_id = $1 and ws_id <> $2)",
+        )
+        .bind(user_id)
+        .bind(actor.ws_id)
+        .fetch_one(&self.pool)
+        .await?;
+        if elsewhere {
+            return Err(AppError::InvalidInput(format!(
+                "user {} is a member of other workspaces, remove it from the workspace instead",
+                user_id
+            )));
+        }
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            "update users set deactivated_at = now() where id = $1 and deactivated_at is null",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidInput(format!(
+                "user {} is already deactivated",
+                user_id
+            )));
+        }
+        self.revoke_user_credentials(user_id, &mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// reactivate a deactivated user, a deleted user can't be reactivated
+    pub async fn reactivate_user(&self, actor: &User, user_id: i64) -> Result<(), AppError> {
+        self.check_outranks(actor, user_id).await?;
+        let ret = sqlx::query(
+            r#"
+            update users set deactivated_at = null
+            where id = $1 and deactivated_at is not null and deleted_at is null
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidInput(format!(
+                "user {} is not deactivated",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// delete the account of the user, the row is anonymized so its messages are kept as "deleted user".
+    /// A workspace owner must transfer the ownership first.
+    pub async fn delete_account(&self, user: &User, input: &DeleteAccount) -> Result<(), AppError> {
+        let (owner,): (bool,) =
+            sqlx::query_as("select exists(select 1 from workspaces where owner_id = $1)")
+                .bind(user.id)
+                .fetch_one(&self.pool)
+                .await?;
+        if owner {
+            return Err(AppError::InvalidInput(
+                "transfer the ownership of your workspaces first".to_string(),
+            ));
+        }
+        if !self.password_hash(user.id).await?.is_empty() {
+            let password = input.password.as_deref().unwrap_or_default();
+            self.confirm_password(user, password).await?;
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            update users set fullname = $2, email = 'deleted-' || id || '@deleted.invalid',
+                password_hash = '', title = null, timezone = null, avatar = null,
+                deactivated_at = coalesce(deactivated_at, now()), deleted_at = now()
+            where id = $1
+            "#,
+        )
+        .bind(user.id)
+        .bind(DELETED_USER_NAME)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("delete from workspace_members where user_id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        self.leave_chats(user.id, None, &mut tx).await?;
+        for table in [
+            "user_totp",
+            "totp_recovery_codes",
+            "mfa_challenges",
+            "user_identities",
+            "password_reset_tokens",
+            "email_verification_tokens",
+        ] {
+            sqlx::query(&format!("delete from {} where user_id = $1", table))
+                .bind(user.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            "update personal_access_tokens set revoked_at = now() where user_id = $1 and revoked_at is null",
+        )
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        self.revoke_user_credentials(user.id, &mut tx).await?;
+        tx.commit().await?;
+        self.reset_signin_failures(&user.email).await
+    }
+
+    /// zip archive of the profile(profile.json), the sent messages(messages.json)
+    /// and the files of them and the avatar(files/...)
+    pub async fn export_personal_data(&self, user: &User) -> Result<Vec<u8>, AppError> {
+        let profile = self.fetch_profile(user).await?;
+        let workspaces = self.list_user_workspaces(user.id, user.ws_id).await?;
+        let messages: Vec<Message> = sqlx::query_as(
+            r#"
+            select id, chat_id, sender_id, content, files, created_at
+            from messages where sender_id = $1
+            order by id asc
+            "#,
+        )
+        .bind(user.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let urls: BTreeSet<&String> = messages
+            .iter()
+            .flat_map(|m| m.files.iter())
+            .chain(profile.avatar.iter())
+            .collect();
+        let mut files = Vec::new();
+        for url in urls {
+            let path = ChatFile::from_str(url)?.path(&self.config.server.base_dir);
+            // files removed from the disk are skipped
+            if let Ok(data) = fs::read(&path).await {
+                files.push((url.trim_start_matches('/').to_string(), data));
+            }
+        }
+        let profile = serde_json::to_vec_pretty(&ExportedProfile {
+            profile,
+            workspaces,
+        })
+        .expect("failed to serialize profile");
+        let messages = serde_json::to_vec_pretty(&messages).expect("failed to serialize messages");
+
+        tokio::task::spawn_blocking(move || {
+            let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            let entries = [
+                ("profile.json".to_string(), profile),
+                ("messages.json".to_string(), messages),
+            ];
+            for (name, data) in entries.into_iter().chain(files) {
+                zip.start_file(name, SimpleFileOptions::default())
+                    .map_err(|e| AppError::IOError(e.into()))?;
+                zip.write_all(&data)?;
+            }
+            let cursor = zip.finish().map_err(|e| AppError::IOError(e.into()))?;
+            Ok(cursor.into_inner())
+        })
+        .await
+        .map_err(|e| AppError::IOError(e.into()))?
+    }
+
+    /// the target must be a member of the workspace of the actor, with a lower role
+    async fn check_outranks(&self, actor: &User, user_id: i64) -> Result<(), AppError> {
+        let actor_role = self.workspace_role(actor).await?;
+        let target = User {
+            id: user_id,
+            ws_id: actor.ws_id,
+            ..actor.clone()
+        };
+        let role = match self.workspace_role(&target).await {
+            Err(AppError::NotFound(_)) => {
+                return Err(AppError::NotFound(format!("user {} not found", user_id)))
+            }
+            ret => ret?,
+        };
+        if !actor_role.can(WorkspaceAction::DeactivateUser) || role >= actor_role {
+            return Err(
+                PermissionDenied::new(Permission::Action(WorkspaceAction::DeactivateUser)).into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// revoke the sessions and refresh tokens, and notify `user_deactivated`
+    async fn revoke_user_credentials(
+        &self,
+        user_id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            "update sessions set revoked_at = now() where user_id = $1 and revoked_at is null",
+        )
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query(
+            "update refresh_tokens set revoked_at = now() where user_id = $1 and revoked_at is null",
+        )
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query(
+            "select pg_notify('user_deactivated', json_build_object('user_id', $1::bigint)::text)",
+        )
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::SigninUser;
+    use anyhow::Result;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn deactivate_user_should_follow_roles() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let admin = state.find_user_by_id(2).await?.unwrap();
+        let member = state.find_user_by_id(3).await?.unwrap();
+
+        // a member can't deactivate anyone
+        let ret = state.deactivate_user(&member, 4).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.deactivate_user(&admin, 3).await?;
+        let ret = state.ensure_user_active(3).await;
+        assert!(matches!(ret, Err(AppError::AccountDeactivated)));
+        let ret = state.deactivate_user(&admin, 3).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        state.reactivate_user(&admin, 3).await?;
+        state.ensure_user_active(3).await?;
+
+        // a user of another workspace is not locked out of it
+        sqlx::query("insert into workspace_members (ws_id, user_id) values (3, 4)")
+            .execute(&state.pool)
+            .await?;
+        let ret = state.deactivate_user(&admin, 4).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        state.ensure_user_active(4).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_account_should_anonymize_user() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(6).await?.unwrap();
+        sqlx::query("insert into messages(chat_id, sender_id, content) values (2, $1, 'bye')")
+            .bind(user.id)
+            .execute(&state.pool)
+            .await?;
+
+        let input = DeleteAccount {
+            password: Some("wrong-password".to_string()),
+        };
+        let ret = state.delete_account(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::IncorrectPassword)));
+        state.reset_signin_failures(&user.email).await?;
+
+        let input = DeleteAccount {
+            password: Some("Jiajia520".to_string()),
+        };
+        state.delete_account(&user, &input).await?;
+        let signin = SigninUser::new(&user.email, "Jiajia520");
+        assert!(state.verify_user(signin).await?.is_none());
+        let deleted = state.find_user_by_id(6).await?.unwrap();
+        assert_eq!(deleted.fullname, DELETED_USER_NAME);
+        assert!(state.fetch_chat_user_by_ids(2, &[6]).await?.is_empty());
+        let (kept,): (i64,) = sqlx::query_as("select count(*) from messages where sender_id = $1")
+            .bind(user.id)
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(kept, 1);
+
+        // an owner must transfer its workspaces first
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let ret = state.delete_account(&owner, &Default::default()).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_personal_data_should_zip_profile_and_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let data = state.export_personal_data(&user).await?;
+
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+        let mut profile = String::new();
+        zip.by_name("profile.json")?.read_to_string(&mut profile)?;
+        let profile: serde_json::Value = serde_json::from_str(&profile)?;
+        assert_eq!(profile["email"], user.email.as_str());
+        assert!(profile["workspaces"].as_array().is_some());
+        let messages: Vec<Message> = serde_json::from_reader(zip.by_name("messages.json")?)?;
+        assert!(messages.iter().all(|m| m.sender_id == user.id));
+        Ok(())
+    }
+}
@@ -1,7 +1,14 @@
-use crate::{error::AppError, AppState};
-use chat_core::{Chat, ChatType};
+use crate::{error::AppError, AppState, ChatFile};
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    Chat, ChatMemberRole, ChatType, User, WorkspaceAction, WorkspaceRole,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use sqlx::{FromRow, PgConnection, PgExecutor, Postgres, Transaction};
+use std::str::FromStr;
+use tokio::fs;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 pub struct CreateChat {
@@ -10,9 +17,88 @@ pub struct CreateChat {
     pub public: bool,
 }
 
+/// page size of the chat list if `limit` is not set
+const DEFAULT_CHAT_LIMIT: u64 = 50;
+const MAX_CHAT_LIMIT: u64 = 200;
+
+/// the chats of the current user, newest first
+#[derive(Debug, Serialize, Deserialize, Default, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+pub struct ListChats {
+    /// list the archived chats instead
+    #[serde(default)]
+    pub archived: bool,
+    /// only the chats of the type
+    #[serde(rename = "type")]
+    #[param(rename = "type", nullable)]
+    pub chat_type: Option<ChatType>,
+    /// include the public channels the user hasn't joined
+    #[serde(default)]
+    pub public: bool,
+    /// the chats with smaller ids, the last id of the previous page
+    #[param(nullable)]
+    pub last_id: Option<i64>,
+    /// 50 by default, at most 200
+    #[param(nullable)]
+    pub limit: Option<u64>,
+}
+
+/// rename the chat and add or remove members, fields not set are kept
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct UpdateChat {
+    /// an empty name clears it, a channel must have a name
+    pub name: Option<String>,
+    #[serde(default)]
+    pub add_members: Vec<i64>,
+    #[serde(default)]
+    pub remove_members: Vec<i64>,
+}
+
+/// a public channel of the workspace, members can join it by themselves
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, ToSchema)]
+pub struct PublicChannel {
+    pub id: i64,
+    pub name: String,
+    pub member_count: i64,
+    /// the current user is a member of it
+    pub joined: bool,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// most user ids in a `chat_updated` payload, pg_notify payloads must be shorter than 8000 bytes
+const NOTIFY_CHUNK_SIZE: usize = 300;
+
+/// what happened to a chat, see `notify_chat_updated`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChatOp {
+    Insert,
+    Update,
+    /// more users removed by the previous update
+    Remove,
+    Archive,
+    Delete,
+}
+
+/// payload of `chat_updated`, notify_server loads the chat and its members by the id
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChatUpdated {
+    pub(crate) op: ChatOp,
+    pub(crate) chat_id: i64,
+    pub(crate) ws_id: i64,
+    /// the users removed from the chat, all the members of a deleted chat
+    pub(crate) removed: Vec<i64>,
+}
+
 impl AppState {
-    /// ws_id: extract from jwt token
-    pub async fn create_chat(&self, input: CreateChat, ws_id: u64) -> Result<Chat, AppError> {
+    /// ws_id: extract from jwt token, the creator is the owner of the chat if it's a member
+    pub async fn create_chat(
+        &self,
+        input: CreateChat,
+        ws_id: u64,
+        creator_id: i64,
+    ) -> Result<Chat, AppError> {
         let len = input.members.len();
         if len < 2 {
             return Err(AppError::CreateChatError(
@@ -24,11 +110,11 @@ impl AppState {
                 "Group chat with more than 8, so name is required".to_string(),
             ));
         }
-        // verity all members is exist
-        let chat_users = self.fetch_chat_user_by_ids(&input.members).await?;
+        // verity all members are in the workspace
+        let chat_users = self.fetch_chat_user_by_ids(ws_id, &input.members).await?;
         if chat_users.len() != len {
             return Err(AppError::CreateChatError(
-                "Some members not exist".to_string(),
+                "Some members not exist in the workspace".to_string(),
             ));
         }
 
@@ -43,34 +129,71 @@ impl AppState {
                 }
             }
         };
-        // r#"
-        // INSERT INTO chats (ws_id, name, type, members)
-        // VALUES ($1, $2, $3, $4)
-        // RETURNING id, ws_id, name, type, members, created_at
-        // "#,
-        let chat = sqlx::query_as(
+        let mut tx = self.pool.begin().await?;
+        let (id,): (i64,) = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
-        "#,
+            INSERT INTO chats (ws_id, name, type)
+            VALUES ($1, $2, $3)
+            RETURNING id
+            "#,
         )
         .bind(ws_id as i64)
         .bind(input.name)
         .bind(chat_type)
-        .bind(&input.members)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        let (owners, members): (Vec<i64>, Vec<i64>) =
+            input.members.iter().partition(|id| **id == creator_id);
+        self.add_chat_members(id, &owners, ChatMemberRole::Owner, &mut tx)
+            .await?;
+        self.add_chat_members(id, &members, ChatMemberRole::Member, &mut tx)
+            .await?;
+        let chat = self
+            .fetch_chat(id, &mut *tx)
+            .await?
+            .expect("chat is inserted");
+        self.notify_chat_updated(ChatOp::Insert, &chat, &[], &mut tx)
+            .await?;
+        tx.commit().await?;
 
         Ok::<Chat, AppError>(chat)
     }
 
+    /// the `general` public channel every new workspace starts with
+    pub async fn create_general_channel(
+        &self,
+        ws_id: i64,
+        owner_id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<Chat, AppError> {
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, name, type)
+            VALUES ($1, 'general', 'public_channel')
+            RETURNING id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_one(&mut **tx)
+        .await?;
+        self.add_chat_members(id, &[owner_id], ChatMemberRole::Owner, tx)
+            .await?;
+        let chat = self
+            .fetch_chat(id, &mut **tx)
+            .await?
+            .expect("chat is inserted");
+        self.notify_chat_updated(ChatOp::Insert, &chat, &[], tx)
+            .await?;
+        Ok(chat)
+    }
+
+    /// the chats not archived
     pub async fn fetch_chats_all(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let recs = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, chat_members_of(id) AS members, archived_at, created_at
             FROM chats
-            WHERE ws_id = $1
+            WHERE ws_id = $1 AND archived_at IS NULL
             "#,
         )
         .bind(ws_id as i64)
@@ -79,28 +202,552 @@ impl AppState {
         Ok(recs)
     }
 
+    /// the chats of the user in its workspace, the chats it's not a member of are never listed
+    /// but the public channels if `public` is set
+    pub async fn list_chats(&self, user: &User, opts: &ListChats) -> Result<Vec<Chat>, AppError> {
+        let limit = opts
+            .limit
+            .unwrap_or(DEFAULT_CHAT_LIMIT)
+            .clamp(1, MAX_CHAT_LIMIT);
+        let recs = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, chat_members_of(c.id) AS members, c.archived_at, c.created_at
+            FROM chats c
+            WHERE c.ws_id = $1
+                AND (EXISTS(SELECT 1 FROM chat_members m WHERE m.chat_id = c.id AND m.user_id = $2)
+                    OR ($3 AND c.type = 'public_channel'))
+                AND (c.archived_at IS NOT NULL) = $4
+                AND ($5::chat_type IS NULL OR c.type = $5)
+                AND c.id < $6
+            ORDER BY c.id DESC
+            LIMIT $7
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(opts.public)
+        .bind(opts.archived)
+        .bind(&opts.chat_type)
+        .bind(opts.last_id.unwrap_or(i64::MAX))
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(recs)
+    }
+
+    /// the chat if the user is a member of it, or it's a public channel of the workspace of the user
+    pub async fn fetch_visible_chat(&self, id: u64, user: &User) -> Result<Option<Chat>, AppError> {
+        let chat = self.fetch_chat_by_id(id).await?.filter(|c| {
+            c.ws_id == user.ws_id
+                && (c.r#type == ChatType::PublicChannel || c.members.contains(&user.id))
+        });
+        Ok(chat)
+    }
+
     pub async fn fetch_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
+        self.fetch_chat(id as _, &self.pool).await
+    }
+
+    async fn fetch_chat(
+        &self,
+        id: i64,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<Option<Chat>, AppError> {
         let rec = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, chat_members_of(id) AS members, archived_at, created_at
             FROM chats
             WHERE id = $1
             "#,
         )
-        .bind(id as i64)
-        .fetch_optional(&self.pool)
+        .bind(id)
+        .fetch_optional(executor)
         .await?;
         Ok(rec)
     }
 
-    pub async fn is_chat_member(&self, chat_id: i64, user_id: u64) -> Result<bool, AppError> {
-        let is_member = sqlx::query(r#"select 1 cnt from chats where id=$1 and $2 = any(members)"#)
+    /// lock the chat until the transaction ends, so its members are checked and changed at once
+    async fn lock_chat(
+        &self,
+        id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<Chat, AppError> {
+        // the members are read after the lock is acquired, not from the snapshot before it
+        sqlx::query("SELECT id FROM chats WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat with id {} not found", id)))?;
+        self.fetch_chat(id, &mut **tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat with id {} not found", id)))
+    }
+
+    /// the public channels not archived, with their member counts
+    pub async fn fetch_public_channels(
+        &self,
+        ws_id: u64,
+        user_id: i64,
+    ) -> Result<Vec<PublicChannel>, AppError> {
+        let recs = sqlx::query_as(
+            r#"
+            SELECT c.id, coalesce(c.name, '') AS name, c.created_at,
+                (SELECT count(*) FROM chat_members m WHERE m.chat_id = c.id) AS member_count,
+                EXISTS(SELECT 1 FROM chat_members m WHERE m.chat_id = c.id AND m.user_id = $2) AS joined
+            FROM chats c
+            WHERE c.ws_id = $1 AND c.type = 'public_channel' AND c.archived_at IS NULL
+            ORDER BY c.name, c.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(recs)
+    }
+
+    /// join a public channel of the workspace of the user
+    pub async fn join_chat(&self, id: u64, user: &User) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = self
+            .fetch_chat(id as _, &mut *tx)
+            .await?
+            .filter(|c| c.ws_id == user.ws_id && c.r#type == ChatType::PublicChannel)
+            .ok_or_else(|| {
+                AppError::NotFound(format!("public channel with id {} not found", id))
+            })?;
+        if chat.archived_at.is_some() {
+            return Err(AppError::ChatArchived(chat.id));
+        }
+        if chat.members.contains(&user.id) {
+            return Err(AppError::InvalidInput(format!(
+                "user {} is already a member of chat {}",
+                user.id, chat.id
+            )));
+        }
+        self.add_chat_members(chat.id, &[user.id], ChatMemberRole::Member, &mut tx)
+            .await?;
+        let new = self
+            .fetch_chat(chat.id, &mut *tx)
+            .await?
+            .expect("chat is fetched");
+        self.notify_chat_updated(ChatOp::Update, &new, &[], &mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(new)
+    }
+
+    /// leave a channel, single chats and groups are changed with `update_chat` instead
+    pub async fn leave_chat(&self, id: u64, user_id: i64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = self.lock_chat(id as _, &mut tx).await?;
+        if !matches!(
+            chat.r#type,
+            ChatType::PublicChannel | ChatType::PrivateChannel
+        ) {
+            return Err(AppError::InvalidInput(
+                "only channels can be left".to_string(),
+            ));
+        }
+        if chat.members == [user_id] {
+            return Err(AppError::InvalidInput(
+                "the last member can't leave a channel".to_string(),
+            ));
+        }
+        let ret = sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat.id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidInput(format!(
+                "user {} is not a member of the chat",
+                user_id
+            )));
+        }
+        self.notify_chat_updated(ChatOp::Update, &chat, &[user_id], &mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// a single chat becomes a group once a member is added, a group never becomes a single chat
+    /// Renaming it or removing others needs the owner of the chat or `ManageChats`,
+    /// other members can only add members and remove themselves.
+    pub async fn update_chat(
+        &self,
+        id: u64,
+        input: UpdateChat,
+        actor: &User,
+        role: WorkspaceRole,
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = self.lock_chat(id as _, &mut tx).await?;
+
+        if chat.archived_at.is_some() {
+            return Err(AppError::ChatArchived(chat.id));
+        }
+
+        let name = match &input.name {
+            Some(name) => {
+                let name = name.trim();
+                if name.chars().count() > 64 {
+                    return Err(AppError::InvalidInput(
+                        "name must be at most 64 characters".to_string(),
+                    ));
+                }
+                (!name.is_empty()).then(|| name.to_string())
+            }
+            None => chat.name.clone(),
+        };
+
+        let renamed = name != chat.name;
+        let removes_others = input.remove_members.iter().any(|id| *id != actor.id);
+        if renamed || removes_others {
+            self.ensure_chat_owner_or(chat.id, actor.id, role, WorkspaceAction::ManageChats)
+                .await?;
+        }
+
+        let mut members = chat.members.clone();
+        let mut removed = Vec::new();
+        for user_id in &input.remove_members {
+            if !members.contains(user_id) {
+                return Err(AppError::InvalidInput(format!(
+                    "user {} is not a member of the chat",
+                    user_id
+                )));
+            }
+            members.retain(|id| id != user_id);
+            removed.push(*user_id);
+        }
+        let mut added: Vec<i64> = input
+            .add_members
+            .iter()
+            .copied()
+            .filter(|id| !members.contains(id))
+            .collect();
+        added.sort_unstable();
+        added.dedup();
+        if !added.is_empty() {
+            let users = self.fetch_chat_user_by_ids(chat.ws_id as _, &added).await?;
+            if users.len() != added.len() {
+                return Err(AppError::InvalidInput(
+                    "Some members not exist in the workspace".to_string(),
+                ));
+            }
+            members.extend(&added);
+        }
+
+        let chat_type = match chat.r#type {
+            ChatType::PublicChannel | ChatType::PrivateChannel => {
+                if name.is_none() {
+                    return Err(AppError::InvalidInput(
+                        "a channel must have a name".to_string(),
+                    ));
+                }
+                if members.is_empty() {
+                    return Err(AppError::InvalidInput(
+                        "a channel must have a member".to_string(),
+                    ));
+                }
+                chat.r#type.clone()
+            }
+            ChatType::Single if name.is_some() => {
+                return Err(AppError::InvalidInput(
+                    "a single chat can't be renamed".to_string(),
+                ));
+            }
+            ChatType::Single | ChatType::Group => {
+                if members.len() < 2 {
+                    return Err(AppError::InvalidInput(
+                        "members must be more than 2".to_string(),
+                    ));
+                }
+                if members.len() > 8 && name.is_none() {
+                    return Err(AppError::InvalidInput(
+                        "Group chat with more than 8, so name is required".to_string(),
+                    ));
+                }
+                if members.len() > 2 {
+                    ChatType::Group
+                } else {
+                    chat.r#type.clone()
+                }
+            }
+        };
+
+        sqlx::query("UPDATE chats SET name = $2, type = $3 WHERE id = $1")
+            .bind(chat.id)
+            .bind(name)
+            .bind(chat_type)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND user_id = ANY($2)")
+            .bind(chat.id)
+            .bind(&removed)
+            .execute(&mut *tx)
+            .await?;
+        self.add_chat_members(chat.id, &added, ChatMemberRole::Member, &mut tx)
+            .await?;
+        let new = self
+            .fetch_chat(chat.id, &mut *tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat with id {} not found", id)))?;
+        self.notify_chat_updated(ChatOp::Update, &new, &removed, &mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(new)
+    }
+
+    /// archive the chat, or restore it if `archived` is false
+    pub async fn archive_chat(&self, id: u64, archived: bool) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let old: Option<Chat> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_members_of(id) AS members, archived_at, created_at
+            FROM chats
+            WHERE id = $1 AND (archived_at IS NOT NULL) <> $2
+            FOR UPDATE
+            "#,
+        )
+        .bind(id as i64)
+        .bind(archived)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(old) = old else {
+            let state = if archived { "archived" } else { "active" };
+            return Err(AppError::InvalidInput(format!(
+                "chat {} not found or already {}",
+                id, state
+            )));
+        };
+        let (archived_at,) = sqlx::query_as(
+            r#"
+                UPDATE chats SET archived_at = CASE WHEN $2 THEN now() END
+                WHERE id = $1
+                RETURNING archived_at
+                "#,
+        )
+        .bind(old.id)
+        .bind(archived)
+        .fetch_one(&mut *tx)
+        .await?;
+        let chat = Chat {
+            archived_at,
+            ..old.clone()
+        };
+        let op = if archived {
+            ChatOp::Archive
+        } else {
+            ChatOp::Update
+        };
+        self.notify_chat_updated(op, &chat, &[], &mut tx).await?;
+        tx.commit().await?;
+        Ok(chat)
+    }
+
+    /// delete the chat with its messages, the files no longer referenced are removed
+    pub async fn delete_chat(&self, id: u64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = self
+            .fetch_chat(id as _, &mut *tx)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat with id {} not found", id)))?;
+        let files: Vec<(String,)> =
+            sqlx::query_as("SELECT DISTINCT unnest(files) FROM messages WHERE chat_id = $1")
+                .bind(id as i64)
+                .fetch_all(&mut *tx)
+                .await?;
+        sqlx::query("DELETE FROM messages WHERE chat_id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM chats WHERE id = $1")
+            .bind(id as i64)
+            .execute(&mut *tx)
+            .await?;
+        self.notify_chat_updated(ChatOp::Delete, &chat, &chat.members, &mut tx)
+            .await?;
+        tx.commit().await?;
+
+        for (url,) in files {
+            let (referenced,): (bool,) = sqlx::query_as(
+                r#"
+                SELECT EXISTS(SELECT 1 FROM messages WHERE $1 = ANY(files))
+                    OR EXISTS(SELECT 1 FROM users WHERE avatar = $1)
+                "#,
+            )
+            .bind(&url)
+            .fetch_one(&self.pool)
+            .await?;
+            if referenced {
+                continue;
+            }
+            if let Ok(file) = ChatFile::from_str(&url) {
+                // the file may be removed already
+                let _ = fs::remove_file(file.path(&self.config.server.base_dir)).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// archived chats are read-only
+    pub async fn ensure_chat_writable(&self, chat_id: i64) -> Result<(), AppError> {
+        let archived = sqlx::query("SELECT 1 FROM chats WHERE id = $1 AND archived_at IS NOT NULL")
             .bind(chat_id)
-            .bind(user_id as i64)
             .fetch_optional(&self.pool)
             .await?;
+        if archived.is_some() {
+            return Err(AppError::ChatArchived(chat_id));
+        }
+        Ok(())
+    }
+
+    pub async fn is_chat_member(&self, chat_id: i64, user_id: u64) -> Result<bool, AppError> {
+        let is_member =
+            sqlx::query(r#"select 1 cnt from chat_members where chat_id = $1 and user_id = $2"#)
+                .bind(chat_id)
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
         Ok(is_member.is_some())
     }
+
+    /// the user is a member of the chat, and the chat is in the workspace of the user's token
+    pub async fn is_workspace_chat_member(
+        &self,
+        chat_id: i64,
+        user: &User,
+    ) -> Result<bool, AppError> {
+        let is_member = sqlx::query(
+            r#"
+            select 1 from chat_members m join chats c on c.id = m.chat_id
+            where m.chat_id = $1 and m.user_id = $2 and c.ws_id = $3
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(is_member.is_some())
+    }
+
+    /// remove the user from its chats, of the workspace if `ws_id` is set, the chats are notified as updated
+    pub(crate) async fn leave_chats(
+        &self,
+        user_id: i64,
+        ws_id: Option<i64>,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), AppError> {
+        let chats: Vec<Chat> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, chat_members_of(c.id) AS members, c.archived_at, c.created_at
+            FROM chats c JOIN chat_members m ON m.chat_id = c.id
+            WHERE m.user_id = $1 AND ($2::bigint IS NULL OR c.ws_id = $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_all(&mut **tx)
+        .await?;
+        let ids: Vec<i64> = chats.iter().map(|c| c.id).collect();
+        sqlx::query("DELETE FROM chat_members WHERE user_id = $1 AND chat_id = ANY($2)")
+            .bind(user_id)
+            .bind(&ids)
+            .execute(&mut **tx)
+            .await?;
+        for chat in chats {
+            self.notify_chat_updated(ChatOp::Update, &chat, &[user_id], tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// the owner of the chat can manage it, other users need `action` in the workspace
+    pub(crate) async fn ensure_chat_owner_or(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        role: WorkspaceRole,
+        action: WorkspaceAction,
+    ) -> Result<(), AppError> {
+        if role.can(action)
+            || self.chat_member_role(chat_id, user_id).await? == Some(ChatMemberRole::Owner)
+        {
+            return Ok(());
+        }
+        Err(PermissionDenied::new(Permission::Action(action)).into())
+    }
+
+    async fn chat_member_role(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Option<ChatMemberRole>, AppError> {
+        let role: Option<(ChatMemberRole,)> =
+            sqlx::query_as("SELECT role FROM chat_members WHERE chat_id = $1 AND user_id = $2")
+                .bind(chat_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(role.map(|(role,)| role))
+    }
+
+    /// users already in the chat are skipped
+    async fn add_chat_members(
+        &self,
+        chat_id: i64,
+        user_ids: &[i64],
+        role: ChatMemberRole,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id, role)
+            SELECT $1, unnest($2::bigint[]), $3
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_ids)
+        .bind(role)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// tell notify_server about the chat, the removed users are sent in chunks
+    pub(crate) async fn notify_chat_updated(
+        &self,
+        op: ChatOp,
+        chat: &Chat,
+        removed: &[i64],
+        conn: &mut PgConnection,
+    ) -> Result<(), AppError> {
+        let mut chunks = removed.chunks(NOTIFY_CHUNK_SIZE);
+        let mut payload = ChatUpdated {
+            op,
+            chat_id: chat.id,
+            ws_id: chat.ws_id,
+            removed: chunks.next().unwrap_or_default().to_vec(),
+        };
+        loop {
+            let json = serde_json::to_string(&payload).expect("failed to serialize chat_updated");
+            sqlx::query("SELECT pg_notify('chat_updated', $1)")
+                .bind(json)
+                .execute(&mut *conn)
+                .await?;
+            let Some(chunk) = chunks.next() else {
+                return Ok(());
+            };
+            // the members are told once, a deleted chat is told to each chunk
+            if payload.op != ChatOp::Delete {
+                payload.op = ChatOp::Remove;
+            }
+            payload.removed = chunk.to_vec();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -121,16 +768,22 @@ impl CreateChat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::messages::CreateMessage;
+    use sqlx::{migrate::Migrator, postgres::PgListener, Connection, Executor, PgPool};
 
     #[tokio::test]
     async fn test_create_single_chat_should_work() -> anyhow::Result<()> {
         // let (_tdb, pool) = get_test_pool(None).await;
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateChat::new("", &[1, 2], false);
-        let chat = state.create_chat(input, 1).await.unwrap();
-        assert_eq!(chat.ws_id, 1);
+        let input = CreateChat::new("", &[2, 3], false);
+        let chat = state.create_chat(input, 2, 2).await.unwrap();
+        assert_eq!(chat.ws_id, 2);
         assert_eq!(chat.r#type, ChatType::Single);
-        assert_eq!(chat.members, vec![1, 2]);
+        assert_eq!(chat.members, vec![2, 3]);
+        // user 1 isn't a member of workspace 2
+        let input = CreateChat::new("", &[1, 2], false);
+        let ret = state.create_chat(input, 2, 2).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
         Ok(())
     }
 
@@ -138,7 +791,7 @@ mod tests {
     async fn test_create_public_named_chat_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateChat::new("test-chat", &[2, 3, 4], true);
-        let chat = state.create_chat(input, 2).await.unwrap();
+        let chat = state.create_chat(input, 2, 2).await.unwrap();
         assert_eq!(chat.ws_id, 2);
         assert_eq!(chat.members.len(), 3);
         assert_eq!(chat.r#type, ChatType::PublicChannel);
@@ -166,6 +819,405 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn list_chats_should_follow_membership() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let ids = |chats: Vec<Chat>| chats.iter().map(|c| c.id).collect::<Vec<_>>();
+
+        // chat 4 is a public channel user 2 hasn't joined
+        let chats = state.list_chats(&user, &ListChats::default()).await?;
+        assert_eq!(ids(chats), vec![5, 3, 2]);
+        let opts = ListChats {
+            public: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(state.list_chats(&user, &opts).await?), vec![5, 4, 3, 2]);
+        let opts = ListChats {
+            chat_type: Some(ChatType::Single),
+            ..Default::default()
+        };
+        assert_eq!(ids(state.list_chats(&user, &opts).await?), vec![3]);
+        let opts = ListChats {
+            last_id: Some(5),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(ids(state.list_chats(&user, &opts).await?), vec![3]);
+
+        assert!(state.fetch_visible_chat(4, &user).await?.is_some());
+        let other = state.find_user_by_id(6).await?.unwrap();
+        assert!(state.fetch_visible_chat(2, &other).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_validate() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(2).await?.unwrap();
+        let member = WorkspaceRole::Member;
+        let chat = state
+            .create_chat(CreateChat::new("", &[2, 3], false), 2, 2)
+            .await?;
+
+        // a single chat becomes a group
+        let input = UpdateChat {
+            add_members: vec![4, 4, 3],
+            ..Default::default()
+        };
+        let chat = state
+            .update_chat(chat.id as _, input, &owner, member)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::Group);
+        assert_eq!(chat.members, vec![2, 3, 4]);
+
+        let input = UpdateChat {
+            name: Some(" team ".to_string()),
+            remove_members: vec![4],
+            ..Default::default()
+        };
+        let chat = state
+            .update_chat(chat.id as _, input, &owner, member)
+            .await?;
+        assert_eq!(chat.name.as_deref(), Some("team"));
+        assert_eq!(chat.r#type, ChatType::Group);
+        assert_eq!(chat.members, vec![2, 3]);
+
+        // user 1 isn't a member of workspace 2
+        let input = UpdateChat {
+            add_members: vec![1],
+            ..Default::default()
+        };
+        let ret = state.update_chat(chat.id as _, input, &owner, member).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let input = UpdateChat {
+            remove_members: vec![3],
+            ..Default::default()
+        };
+        let ret = state.update_chat(chat.id as _, input, &owner, member).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let channel = state
+            .create_chat(CreateChat::new("news", &[2, 3], true), 2, 2)
+            .await?;
+        let input = UpdateChat {
+            name: Some("".to_string()),
+            ..Default::default()
+        };
+        let ret = state
+            .update_chat(channel.id as _, input, &owner, member)
+            .await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_check_permissions() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let member = WorkspaceRole::Member;
+        let user3 = state.find_user_by_id(3).await?.unwrap();
+        let user4 = state.find_user_by_id(4).await?.unwrap();
+        let channel = state
+            .create_chat(CreateChat::new("news", &[2, 3, 4], false), 2, 2)
+            .await?;
+        let id = channel.id as u64;
+
+        // only the owner and admins can rename it or remove others
+        let input = UpdateChat {
+            name: Some("mine".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_chat(id, input, &user3, member).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let input = UpdateChat {
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let ret = state.update_chat(id, input, &user3, member).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // keeping the name is not a rename
+        let input = UpdateChat {
+            name: Some("news".to_string()),
+            remove_members: vec![3],
+            ..Default::default()
+        };
+        let chat = state.update_chat(id, input, &user3, member).await?;
+        assert_eq!(chat.members, vec![2, 4]);
+
+        let input = UpdateChat {
+            name: Some("renamed".to_string()),
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let chat = state
+            .update_chat(id, input, &user4, WorkspaceRole::Admin)
+            .await?;
+        assert_eq!(chat.name.as_deref(), Some("renamed"));
+
+        // a channel keeps a member
+        let input = UpdateChat {
+            remove_members: vec![4],
+            ..Default::default()
+        };
+        let ret = state.update_chat(id, input, &user4, member).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let ret = state.leave_chat(id, user4.id).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_and_delete_chat_should_work() -> anyhow::Result<()> {
+        let base_dir = std::env::temp_dir().join(uuid::Uuid::now_v7().to_string());
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.server.base_dir = base_dir.clone()).await?;
+        let chat = state.archive_chat(2, true).await?;
+        assert!(chat.archived_at.is_some());
+        assert_eq!(state.fetch_chats_all(2).await?.len(), 3);
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let opts = ListChats {
+            archived: true,
+            ..Default::default()
+        };
+        assert_eq!(state.list_chats(&user, &opts).await?.len(), 1);
+        let ret = state.ensure_chat_writable(2).await;
+        assert!(matches!(ret, Err(AppError::ChatArchived(2))));
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let ret = state
+            .update_chat(2, UpdateChat::default(), &user, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::ChatArchived(2))));
+        let chat = state.archive_chat(2, false).await?;
+        assert!(chat.archived_at.is_none());
+        assert!(state.archive_chat(2, false).await.is_err());
+
+        // a file only sent to the deleted chat is removed
+        let file = ChatFile::new(2, "a.txt", b"hello");
+        let path = file.path(&base_dir);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(&path, b"hello").await?;
+        sqlx::query(
+            "INSERT INTO messages(chat_id, sender_id, content, files) VALUES (2, 2, 'f', $1)",
+        )
+        .bind([file.url()])
+        .execute(&state.pool)
+        .await?;
+        state.delete_chat(2).await?;
+        assert!(state.fetch_chat_by_id(2).await?.is_none());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_and_leave_public_channel_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let channels = state.fetch_public_channels(2, user.id).await?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].id, 4);
+        assert_eq!(channels[0].member_count, 3);
+        assert!(!channels[0].joined);
+
+        let chat = state.join_chat(4, &user).await?;
+        assert_eq!(chat.members, vec![3, 4, 5, 2]);
+        let ret = state.join_chat(4, &user).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let channels = state.fetch_public_channels(2, user.id).await?;
+        assert_eq!(channels[0].member_count, 4);
+        assert!(channels[0].joined);
+
+        // a group can't be joined or left
+        let ret = state.join_chat(2, &user).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let ret = state.leave_chat(2, user.id).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        state.leave_chat(4, user.id).await?;
+        assert!(!state.is_chat_member(4, user.id as _).await?);
+        let ret = state.leave_chat(4, user.id).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_leaves_should_keep_a_channel_member() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for _ in 0..5 {
+            let chat = state
+                .create_chat(CreateChat::new("pair", &[2, 3], false), 2, 2)
+                .await?;
+            let (first, second) = tokio::join!(
+                state.leave_chat(chat.id as _, 2),
+                state.leave_chat(chat.id as _, 3)
+            );
+            assert!(first.is_ok() != second.is_ok());
+            let chat = state.fetch_chat_by_id(chat.id as _).await?.unwrap();
+            assert_eq!(chat.members.len(), 1);
+        }
+
+        // updates are checked against the locked chat too
+        let chat = state
+            .create_chat(CreateChat::new("pair", &[2, 3], false), 2, 2)
+            .await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let input = UpdateChat {
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let (left, updated) = tokio::join!(
+            state.leave_chat(chat.id as _, 3),
+            state.update_chat(chat.id as _, input, &user, WorkspaceRole::Member)
+        );
+        assert!(left.is_ok() != updated.is_ok());
+        let ret = state
+            .update_chat(9999, UpdateChat::default(), &user, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_of_should_follow_join_order() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update chat_members set joined_at = joined_at - interval '1 day' where chat_id = 2 and user_id = 5")
+            .execute(&state.pool)
+            .await?;
+        let (members,): (Vec<i64>,) = sqlx::query_as("select chat_members_of(2)")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(members, vec![5, 2, 3, 4]);
+        let (members,): (Vec<i64>,) = sqlx::query_as("select chat_members_of(100)")
+            .fetch_one(&state.pool)
+            .await?;
+        assert!(members.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_migration_should_backfill_members() -> anyhow::Result<()> {
+        // a database migrated up to the chat_members migration, with chats.members
+        let (tdb, _state) = AppState::new_for_test().await?;
+        let dbname = format!("test_{}", uuid::Uuid::now_v7().simple());
+        let mut conn = PgConnection::connect(&tdb.server_url()).await?;
+        conn.execute(format!(r#"CREATE DATABASE "{}""#, dbname).as_str())
+            .await?;
+        let pool = PgPool::connect(&format!("{}/{}", tdb.server_url(), dbname)).await?;
+        let mut migrator = Migrator::new(std::path::Path::new("../migrations")).await?;
+        let migrations = migrator.migrations.clone();
+        migrator.migrations = migrations
+            .iter()
+            .filter(|m| m.version < 20241019000000)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into();
+        migrator.run(&pool).await?;
+        // the default workspace, user 1 and the general channel with 0 as its member are migrated
+        sqlx::query(
+            r#"
+            INSERT INTO users (ws_id, email, fullname, password_hash)
+            VALUES (1, 'a@legacy', 'a', ''), (1, 'b@legacy', 'b', '')
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO chats (ws_id, name, type, members) VALUES (1, NULL, 'group', '{3,1,2}')",
+        )
+        .execute(&pool)
+        .await?;
+        let general = "INSERT INTO chats (ws_id, name, type, members) VALUES (1, 'general', 'public_channel', '{2,1}')";
+        sqlx::query(general).execute(&pool).await?;
+
+        migrator.migrations = migrations;
+        migrator.run(&pool).await?;
+        let members: Vec<(Vec<i64>,)> =
+            sqlx::query_as("SELECT chat_members_of(id) FROM chats ORDER BY id")
+                .fetch_all(&pool)
+                .await?;
+        // 0 is not a user, it's dropped
+        assert_eq!(members, vec![(vec![],), (vec![1, 2, 3],), (vec![1, 2],)]);
+        // only the workspace owner of the general channel becomes an owner
+        let owners: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT chat_id, user_id FROM chat_members WHERE role = 'owner' ORDER BY chat_id",
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(owners, vec![(3, 1)]);
+        let (column,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM information_schema.columns WHERE table_name = 'chats' AND column_name = 'members')",
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert!(!column);
+
+        pool.close().await;
+        conn.execute(format!(r#"DROP DATABASE "{}""#, dbname).as_str())
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_notifications_should_fit_in_pg_notify() -> anyhow::Result<()> {
+        let (tdb, state) = AppState::new_for_test().await?;
+        let mut listener = PgListener::connect(&tdb.url()).await?;
+        listener
+            .listen_all(["chat_updated", "message_added"])
+            .await?;
+
+        // a channel with more members than a payload can hold
+        sqlx::query(
+            r#"
+            insert into users (ws_id, email, fullname, password_hash)
+            select 2, 'user' || i || '@large.channel', 'user' || i, '' from generate_series(1, 1000) i
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        sqlx::query("insert into workspace_members (ws_id, user_id) select 2, id from users where email like '%@large.channel'")
+            .execute(&state.pool)
+            .await?;
+        let channel = state
+            .create_chat(CreateChat::new("large", &[2, 3], true), 2, 2)
+            .await?;
+        let updated: ChatUpdated = serde_json::from_str(listener.recv().await?.payload())?;
+        assert_eq!(updated.op, ChatOp::Insert);
+        assert_eq!(updated.chat_id, channel.id);
+
+        sqlx::query("insert into chat_members (chat_id, user_id) select $1, id from users where email like '%@large.channel'")
+            .bind(channel.id)
+            .execute(&state.pool)
+            .await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        state
+            .create_message(
+                CreateMessage {
+                    content: "hello".to_string(),
+                    files: vec![],
+                },
+                channel.id,
+                user.id as _,
+            )
+            .await?;
+        let notification = listener.recv().await?;
+        assert_eq!(notification.channel(), "message_added");
+        let payload: serde_json::Value = serde_json::from_str(notification.payload())?;
+        assert_eq!(payload["message"]["chat_id"], channel.id);
+
+        // the 1002 members of the deleted chat are sent in chunks
+        state.delete_chat(channel.id as _).await?;
+        let mut removed = 0;
+        while removed < 1002 {
+            let notification = listener.recv().await?;
+            assert!(notification.payload().len() < 8000);
+            let deleted: ChatUpdated = serde_json::from_str(notification.payload())?;
+            assert_eq!(deleted.op, ChatOp::Delete);
+            assert!(deleted.removed.len() <= NOTIFY_CHUNK_SIZE);
+            removed += deleted.removed.len();
+        }
+        assert_eq!(removed, 1002);
+        Ok(())
+    }
+
     use crate::AppState;
 
     #[tokio::test]
@@ -177,5 +1229,17 @@ mod tests {
 
         let res = state.is_chat_member(2, 6).await.unwrap();
         assert!(!res);
+
+        let chat = state
+            .create_chat(CreateChat::new("", &[3, 6], false), 2, 3)
+            .await
+            .unwrap();
+        assert!(state.is_chat_member(chat.id, 6).await.unwrap());
+        let mut tx = state.pool.begin().await.unwrap();
+        state.leave_chats(6, Some(2), &mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert!(!state.is_chat_member(chat.id, 6).await.unwrap());
+        let chat = state.fetch_chat_by_id(chat.id as _).await.unwrap().unwrap();
+        assert_eq!(chat.members, vec![3]);
     }
 }
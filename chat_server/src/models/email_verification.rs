@@ -0,0 +1,135 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, mailer::Mail, AppState};
+use chat_core::User;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use utoipa::ToSchema;
+
+/// verification token is valid for 1 day
+const VERIFICATION_TOKEN_DURATION: i64 = 60 * 60 * 24;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+impl AppState {
+    /// create a verification token and mail it to the user, previous tokens are invalidated
+    pub async fn send_email_verification(&self, user: &User) -> Result<(), AppError> {
+        let mut conn = self.pool.acquire().await?;
+        let token = self.create_email_verification(user.id, &mut conn).await?;
+        self.mail_email_verification(user, &token).await
+    }
+
+    /// previous tokens are invalidated, e.g. in the signup transaction
+    pub(crate) async fn create_email_verification(
+        &self,
+        user_id: i64,
+        conn: &mut PgConnection,
+    ) -> Result<String, AppError> {
+        sqlx::query(
+            r#"update email_verification_tokens set used_at = now() where user_id = $1 and used_at is null"#,
+        )
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(VERIFICATION_TOKEN_DURATION);
+        sqlx::query(
+            r#"insert into email_verification_tokens (user_id, token_hash, expires_at) values ($1, $2, $3)"#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&mut *conn)
+        .await?;
+        Ok(token)
+    }
+
+    pub(crate) async fn mail_email_verification(
+        &self,
+        user: &User,
+        token: &str,
+    ) -> Result<(), AppError> {
+        let body = format!(
+            "Hi {},\n\nUse the token below to verify your email address, it expires in 1 day.\n\n{}\n",
+            user.fullname, token
+        );
+        let mail = Mail::new(&user.email, "Verify your email address", body);
+        self.mailer.send(&mail).await
+    }
+
+    /// mark the email of the token owner as verified, the token can only be used once
+    pub async fn verify_email(&self, input: &VerifyEmail) -> Result<User, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            update email_verification_tokens set used_at = now()
+            where token_hash = $1 and used_at is null and expires_at > now()
+            returning user_id
+            "#,
+        )
+        .bind(hash_token(&input.token))
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((user_id,)) = user_id else {
+            return Err(AppError::InvalidToken(
+                "email verification token is invalid or expired".to_string(),
+            ));
+        };
+
+        let user = sqlx::query_as(
+            r#"
+            update users set verified_at = coalesce(verified_at, now()) where id = $1
+            returning id, ws_id, fullname, email, verified_at, created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::CreateUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn verify_email_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "new@email.com", "new", "hunter42");
+        let user = state.create_user(&input).await?;
+        assert!(user.verified_at.is_none());
+
+        // the token mailed by signup is invalidated by a new one
+        state.send_email_verification(&user).await?;
+        let mails = state.read_mails("new@email.com").await;
+        assert_eq!(mails.len(), 2);
+        let token = |mail: &str| {
+            mail.lines()
+                .find(|l| l.len() == 64)
+                .expect("token should be in mail")
+                .to_string()
+        };
+        let input = VerifyEmail {
+            token: token(&mails[0]),
+        };
+        let res = state.verify_email(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidToken(_))));
+        let token = token(&mails[1]);
+
+        let input = VerifyEmail { token };
+        let user = state.verify_email(&input).await?;
+        assert!(user.verified_at.is_some());
+
+        let res = state.verify_email(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+}
@@ -10,7 +10,7 @@ use sha1::{Digest, Sha1};
 
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
-        let ext = filename.split('.').last().unwrap_or("txt");
+        let ext = filename.split('.').next_back().unwrap_or("txt");
         let hash = Sha1::digest(data);
         Self {
             ws_id,
@@ -0,0 +1,202 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, oidc::OidcIdentity, AppState};
+use chat_core::User;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// the user must come back from the IdP in 10 minutes
+const LOGIN_STATE_DURATION: i64 = 60 * 10;
+
+/// query parameters the IdP redirects back with
+#[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: String,
+    /// set by the IdP if the user denied the login
+    pub error: Option<String>,
+}
+
+pub struct OidcLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+impl AppState {
+    /// start an OIDC login with a random state, nonce and PKCE code verifier
+    pub async fn create_oidc_login(&self) -> Result<OidcLogin, AppError> {
+        let login = OidcLogin {
+            state: generate_token(),
+            nonce: generate_token(),
+            code_verifier: generate_token(),
+        };
+        let expires_at = Utc::now() + Duration::seconds(LOGIN_STATE_DURATION);
+        sqlx::query(
+            r#"
+            insert into oidc_login_states (state_hash, nonce, code_verifier, expires_at)
+            values ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(hash_token(&login.state))
+        .bind(&login.nonce)
+        .bind(&login.code_verifier)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(login)
+    }
+
+    /// consume the login by the state parameter, it can only be used once
+    pub async fn take_oidc_login(&self, state: &str) -> Result<OidcLogin, AppError> {
+        let rec: Option<(String, String)> = sqlx::query_as(
+            r#"
+            delete from oidc_login_states where state_hash = $1 and expires_at > now()
+            returning nonce, code_verifier
+            "#,
+        )
+        .bind(hash_token(state))
+        .fetch_optional(&self.pool)
+        .await?;
+        let (nonce, code_verifier) = rec
+            .ok_or_else(|| AppError::OidcError("login state is invalid or expired".to_string()))?;
+        Ok(OidcLogin {
+            state: state.to_string(),
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// find the user linked to the IdP identity.
+    /// On first login, link the user with the same verified email, or create one in `workspace`.
+    pub async fn find_or_create_oidc_user(
+        &self,
+        identity: &OidcIdentity,
+        workspace: &str,
+    ) -> Result<User, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            select u.id, u.ws_id, u.fullname, u.email, u.verified_at, u.created_at
+            from user_identities i join users u on i.user_id = u.id
+            where i.issuer = $1 and i.subject = $2
+            "#,
+        )
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(user) = user {
+            return Ok(user);
+        }
+
+        let email = identity
+            .email
+            .as_deref()
+            .ok_or_else(|| AppError::OidcError("id_token has no email".to_string()))?;
+        let mut tx = self.pool.begin().await?;
+        let user = match self.find_user_by_email(email).await? {
+            // an unverified email at the IdP can't take over an existing account
+            Some(_) if !identity.email_verified => {
+                return Err(AppError::EmailAlreadyExists(email.to_string()))
+            }
+            Some(user) => user,
+            None => {
+                // owned by the super user until the user is created, like signing up
+                let (ws, created) = match self.find_workspace_by_name(workspace).await? {
+                    Some(ws) => (ws, false),
+                    None => (self.create_workspace(workspace, 1, &mut *tx).await?, true),
+                };
+                let fullname = identity.name.as_deref().unwrap_or(email);
+                // no password, the user can only sign in by the IdP or after a password reset
+                let user: User = sqlx::query_as(
+                    r#"
+                    insert into users (fullname, email, password_hash, ws_id, verified_at)
+                    values ($1, $2, '', $3, case when $4 then now() end)
+                    returning id, ws_id, fullname, email, verified_at, created_at
+                    "#,
+                )
+                .bind(fullname)
+                .bind(email)
+                .bind(ws.id)
+                .bind(identity.email_verified)
+                .fetch_one(&mut *tx)
+                .await?;
+                self.onboard_user(&ws, created, user.id, &mut tx).await?;
+                user
+            }
+        };
+
+        sqlx::query(
+            r#"insert into user_identities (issuer, subject, user_id) values ($1, $2, $3)"#,
+        )
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn identity(subject: &str, email: &str, email_verified: bool) -> OidcIdentity {
+        OidcIdentity {
+            issuer: "http://idp.test".to_string(),
+            subject: subject.to_string(),
+            email: Some(email.to_string()),
+            email_verified,
+            name: Some("Idp User".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn oidc_login_state_should_be_used_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let login = state.create_oidc_login().await?;
+        let taken = state.take_oidc_login(&login.state).await?;
+        assert_eq!(taken.nonce, login.nonce);
+        assert_eq!(taken.code_verifier, login.code_verifier);
+        let res = state.take_oidc_login(&login.state).await;
+        assert!(matches!(res, Err(AppError::OidcError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_or_create_oidc_user_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state
+            .find_or_create_oidc_user(&identity("sub1", "idp@hkjc.org.hk", true), "idp")
+            .await?;
+        assert_eq!(user.fullname, "Idp User");
+        assert!(user.verified_at.is_some());
+        let ws = state.find_workspace_by_name("idp").await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        // the new workspace is owned by the user, like signing up
+        assert_eq!(ws.owner_id, user.id);
+        let chats = state.fetch_chats_all(ws.id as _).await?;
+        assert_eq!(chats[0].name.as_deref(), Some("general"));
+        assert_eq!(chats[0].members, vec![user.id]);
+
+        // the same identity maps to the same user
+        let again = state
+            .find_or_create_oidc_user(&identity("sub1", "changed@hkjc.org.hk", true), "idp")
+            .await?;
+        assert_eq!(again.id, user.id);
+
+        // an existing user is linked only by a verified email
+        let res = state
+            .find_or_create_oidc_user(&identity("sub2", "zack@email.com", false), "idp")
+            .await;
+        assert!(matches!(res, Err(AppError::EmailAlreadyExists(_))));
+        let linked = state
+            .find_or_create_oidc_user(&identity("sub2", "zack@email.com", true), "idp")
+            .await?;
+        assert_eq!(linked.email, "zack@email.com");
+        Ok(())
+    }
+}
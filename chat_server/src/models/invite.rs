@@ -0,0 +1,229 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, mailer::Mail, AppState};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor};
+use utoipa::ToSchema;
+
+/// invites expire in 7 days by default, 30 days at most
+const DEFAULT_INVITE_DAYS: u32 = 7;
+const MAX_INVITE_DAYS: u32 = 30;
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreateInvite {
+    /// only the user signing up with this email can use it, the code is mailed to it
+    pub email: Option<String>,
+    /// unlimited if not set
+    pub max_uses: Option<i32>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct Invite {
+    pub id: i64,
+    pub ws_id: i64,
+    pub created_by: i64,
+    pub email: Option<String>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    #[schema(value_type=String)]
+    pub expires_at: DateTime<Utc>,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatedInvite {
+    /// only shown once, sign up with it to join the workspace
+    pub code: String,
+    pub invite: Invite,
+}
+
+impl AppState {
+    /// create an invite to the workspace of the user
+    pub async fn create_invite(
+        &self,
+        user: &User,
+        input: &CreateInvite,
+    ) -> Result<CreatedInvite, AppError> {
+        if matches!(input.max_uses, Some(n) if n < 1) {
+            return Err(AppError::InvalidInput(
+                "max_uses must be at least 1".to_string(),
+            ));
+        }
+        let days = input.expires_in_days.unwrap_or(DEFAULT_INVITE_DAYS);
+        if days == 0 || days > MAX_INVITE_DAYS {
+            return Err(AppError::InvalidInput(format!(
+                "invite must expire in 1 to {} days",
+                MAX_INVITE_DAYS
+            )));
+        }
+        let email = input
+            .email
+            .as_deref()
+            .map(str::trim)
+            .filter(|e| !e.is_empty());
+
+        let code = generate_token();
+        let expires_at = Utc::now() + Duration::days(days as i64);
+        let invite: Invite = sqlx::query_as(
+            r#"
+            insert into workspace_invites (ws_id, created_by, code_hash, email, max_uses, expires_at)
+            values ($1, $2, $3, $4, $5, $6)
+            returning id, ws_id, created_by, email, max_uses, uses, expires_at, created_at
+            "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(hash_token(&code))
+        .bind(email)
+        .bind(input.max_uses)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if let Some(email) = email {
+            let body = format!(
+                "Hi,\n\n{} invited you to join the workspace. Sign up with the invite code below, it expires in {} days.\n\n{}\n",
+                user.fullname, days, code
+            );
+            let mail = Mail::new(email, "You are invited to a workspace", body);
+            self.mailer.send(&mail).await?;
+        }
+        Ok(CreatedInvite { code, invite })
+    }
+
+    /// invites of the workspace which can still be used
+    pub async fn list_invites(&self, ws_id: i64) -> Result<Vec<Invite>, AppError> {
+        let invites = sqlx::query_as(
+            r#"
+            select id, ws_id, created_by, email, max_uses, uses, expires_at, created_at
+            from workspace_invites
+            where ws_id = $1 and revoked_at is null and expires_at > now()
+            and (max_uses is null or uses < max_uses)
+            order by id desc
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(invites)
+    }
+
+    pub async fn revoke_invite(&self, id: i64, ws_id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            update workspace_invites set revoked_at = now()
+            where id = $1 and ws_id = $2 and revoked_at is null
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("invite {} not found", id)));
+        }
+        Ok(())
+    }
+
+    /// use the invite to join the workspace, it's counted once the transaction commits
+    pub async fn consume_invite(
+        &self,
+        code: &str,
+        ws_id: i64,
+        email: &str,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            update workspace_invites set uses = uses + 1
+            where code_hash = $1 and ws_id = $2 and revoked_at is null and expires_at > now()
+            and (max_uses is null or uses < max_uses)
+            and (email is null or lower(email) = lower($3))
+            "#,
+        )
+        .bind(hash_token(code))
+        .bind(ws_id)
+        .bind(email)
+        .execute(executor)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidInvite(
+                "invite is invalid, expired or used up".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::CreateUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn invite_should_be_required_to_join_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+
+        let mut input = CreateUser::new("default", "new@email.com", "new", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite(_))));
+
+        let invite = CreateInvite {
+            max_uses: Some(1),
+            ..Default::default()
+        };
+        let created = state.create_invite(&owner, &invite).await?;
+        assert_eq!(state.list_invites(owner.ws_id).await?.len(), 1);
+        input.invite = Some(created.code.clone());
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, owner.ws_id);
+
+        // used up
+        assert!(state.list_invites(owner.ws_id).await?.is_empty());
+        let mut input = CreateUser::new("default", "other@email.com", "other", "hunter42");
+        input.invite = Some(created.code);
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_bound_to_email_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let invite = CreateInvite {
+            email: Some("bound@email.com".to_string()),
+            ..Default::default()
+        };
+        let created = state.create_invite(&owner, &invite).await?;
+        let mails = state.read_mails("bound@email.com").await;
+        assert!(mails[0].contains(&created.code));
+
+        let mut input = CreateUser::new("default", "other@email.com", "other", "hunter42");
+        input.invite = Some(created.code.clone());
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite(_))));
+        // the invite of another workspace can't be used
+        let mut input = CreateUser::new("test1", "bound@email.com", "bound", "hunter42");
+        input.invite = Some(created.code.clone());
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite(_))));
+
+        state.revoke_invite(created.invite.id, owner.ws_id).await?;
+        let mut input = CreateUser::new("default", "bound@email.com", "bound", "hunter42");
+        input.invite = Some(created.code);
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite(_))));
+
+        let created = state.create_invite(&owner, &invite).await?;
+        input.invite = Some(created.code);
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, owner.ws_id);
+        Ok(())
+    }
+}
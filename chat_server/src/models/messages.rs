@@ -2,7 +2,10 @@ use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 
 use crate::{error::AppError, AppState, ChatFile};
-use chat_core::Message;
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    Message, User, WorkspaceAction, WorkspaceRole,
+};
 use std::str::FromStr;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
@@ -29,6 +32,7 @@ impl AppState {
         chat_id: i64,
         user_id: u64,
     ) -> Result<Message, AppError> {
+        self.ensure_chat_writable(chat_id).await?;
         let base_dir = &self.config.server.base_dir;
         if input.content.is_empty() {
             return Err(AppError::CreateMessageError(
@@ -59,6 +63,39 @@ impl AppState {
         Ok(message)
     }
 
+    /// the sender can delete its own messages, admins can delete others' too
+    pub async fn delete_message(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        user: &User,
+        role: WorkspaceRole,
+    ) -> Result<(), AppError> {
+        let sender: Option<(i64,)> =
+            sqlx::query_as(r#"SELECT sender_id FROM messages WHERE id = $1 AND chat_id = $2"#)
+                .bind(message_id)
+                .bind(chat_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some((sender_id,)) = sender else {
+            return Err(AppError::NotFound(format!(
+                "message {} not found",
+                message_id
+            )));
+        };
+        if sender_id != user.id && !role.can(WorkspaceAction::DeleteOthersMessages) {
+            return Err(PermissionDenied::new(Permission::Action(
+                WorkspaceAction::DeleteOthersMessages,
+            ))
+            .into());
+        }
+        sqlx::query(r#"DELETE FROM messages WHERE id = $1"#)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn list_messages(
         &self,
         chat_id: u64,
@@ -112,6 +149,29 @@ mod tests {
         assert_eq!(result.to_string(), "Chat File error: Invalid file url: abc");
     }
 
+    #[tokio::test]
+    async fn delete_others_message_should_need_admin() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let zack = state.find_user_by_id(2).await?.unwrap();
+        // message 2 is sent by user 3
+        let ret = state
+            .delete_message(2, 2, &zack, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state
+            .delete_message(2, 2, &zack, WorkspaceRole::Admin)
+            .await?;
+        // message 1 is sent by zack
+        state
+            .delete_message(2, 1, &zack, WorkspaceRole::Guest)
+            .await?;
+        let ret = state
+            .delete_message(2, 1, &zack, WorkspaceRole::Guest)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_messages_should_work() -> anyhow::Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -1,10 +1,23 @@
+use chat_core::WorkspaceRole;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 use utoipa::ToSchema;
 
+pub mod account;
 pub mod chat;
+pub mod email_verification;
 mod file;
+pub mod identity;
+pub mod invite;
 pub mod messages;
+pub mod password_reset;
+pub mod personal_access_token;
+pub mod profile;
+pub mod session;
+pub mod signin_attempt;
+pub mod token;
+pub mod totp;
+pub mod typing;
 pub mod user;
 pub mod workspace;
 
@@ -20,4 +33,9 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    pub title: Option<String>,
+    pub timezone: Option<String>,
+    /// url of the avatar file
+    pub avatar: Option<String>,
+    pub role: WorkspaceRole,
 }
@@ -0,0 +1,161 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, mailer::Mail, AppState};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// reset token is valid for 1 hour
+const RESET_TOKEN_DURATION: i64 = 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RequestPasswordReset {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+impl AppState {
+    /// create a reset token and mail it to the user.
+    /// unknown emails are ignored, so the caller can't tell whether an email is registered.
+    pub async fn request_password_reset(
+        &self,
+        input: &RequestPasswordReset,
+    ) -> Result<(), AppError> {
+        let Some(user) = self.find_user_by_email(&input.email).await? else {
+            return Ok(());
+        };
+
+        // only the latest token is valid
+        sqlx::query(
+            r#"update password_reset_tokens set used_at = now() where user_id = $1 and used_at is null"#,
+        )
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(RESET_TOKEN_DURATION);
+        sqlx::query(
+            r#"insert into password_reset_tokens (user_id, token_hash, expires_at) values ($1, $2, $3)"#,
+        )
+        .bind(user.id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        let body = format!(
+            "Hi {},\n\nUse the token below to reset your password, it expires in 1 hour.\n\n{}\n\nIf you didn't request it, please ignore this mail.\n",
+            user.fullname, token
+        );
+        let mail = Mail::new(user.email, "Reset your password", body);
+        self.mailer.send(&mail).await
+    }
+
+    /// set the new password with a reset token, the token can only be used once.
+    /// all the sessions and refresh tokens of the user are revoked.
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<(), AppError> {
+        self.password.validate(&input.password)?;
+        let password_hash = self.password.hash(&input.password)?;
+        let mut tx = self.pool.begin().await?;
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            update password_reset_tokens set used_at = now()
+            where token_hash = $1 and used_at is null and expires_at > now()
+            returning user_id
+            "#,
+        )
+        .bind(hash_token(&input.token))
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((user_id,)) = user_id else {
+            return Err(AppError::InvalidToken(
+                "password reset token is invalid or expired".to_string(),
+            ));
+        };
+
+        sqlx::query(r#"update users set password_hash = $1 where id = $2"#)
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"update refresh_tokens set revoked_at = now() where user_id = $1 and revoked_at is null"#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            with revoked as (
+                update sessions set revoked_at = now()
+                where user_id = $1 and revoked_at is null
+                returning id
+            )
+            select pg_notify('session_revoked', json_build_object(
+                'user_id', $1::bigint, 'session_id', id)::text)
+            from revoked
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::SigninUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn reset_password_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("zack@email.com").await?.unwrap();
+        let session = state.create_session(user.id, &Default::default()).await?;
+        let input = RequestPasswordReset {
+            email: "zack@email.com".to_string(),
+        };
+        state.request_password_reset(&input).await?;
+        let mails = state.read_mails("zack@email.com").await;
+        assert_eq!(mails.len(), 1);
+        let token = mails[0]
+            .lines()
+            .find(|l| l.len() == 64)
+            .expect("token should be in mail");
+
+        let input = ResetPassword {
+            token: token.to_string(),
+            password: "new-password".to_string(),
+        };
+        state.reset_password(&input).await?;
+        let user = state
+            .verify_user(SigninUser::new("zack@email.com", "new-password"))
+            .await?;
+        assert!(user.is_some());
+        assert!(!state.touch_session(session).await?);
+
+        // token can only be used once
+        let res = state.reset_password(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_password_with_unknown_email_should_send_nothing() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = RequestPasswordReset {
+            email: "nobody@email.com".to_string(),
+        };
+        state.request_password_reset(&input).await?;
+        assert!(state.read_mails("nobody@email.com").await.is_empty());
+        Ok(())
+    }
+}
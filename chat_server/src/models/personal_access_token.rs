@@ -0,0 +1,228 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, AppState};
+use chat_core::{Scope, User};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// personal access tokens start with it, so they can be told from JWTs
+pub const PERSONAL_ACCESS_TOKEN_PREFIX: &str = "chat_pat_";
+
+/// the longest lifetime of an expiring token, about 10 years
+const MAX_EXPIRES_IN_DAYS: u32 = 3650;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatePersonalAccessToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// never expires if not set, at most 3650 days
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct PersonalAccessToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[schema(value_type=Option<String>)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[schema(value_type=Option<String>)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatedPersonalAccessToken {
+    /// only shown once, use it as the bearer token
+    pub token: String,
+    pub personal_access_token: PersonalAccessToken,
+}
+
+impl AppState {
+    /// the token works in the active workspace of the user
+    pub async fn create_personal_access_token(
+        &self,
+        user: &User,
+        input: &CreatePersonalAccessToken,
+    ) -> Result<CreatedPersonalAccessToken, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.len() > 64 {
+            return Err(AppError::InvalidInput(
+                "token name must be 1 to 64 characters".to_string(),
+            ));
+        }
+        if input.scopes.is_empty() {
+            return Err(AppError::InvalidInput(
+                "token must have at least one scope".to_string(),
+            ));
+        }
+        if input.expires_in_days > Some(MAX_EXPIRES_IN_DAYS) {
+            return Err(AppError::InvalidInput(format!(
+                "token must expire in at most {} days",
+                MAX_EXPIRES_IN_DAYS
+            )));
+        }
+        let mut scopes: Vec<Scope> = vec![];
+        for scope in &input.scopes {
+            if !scopes.contains(scope) {
+                scopes.push(*scope);
+            }
+        }
+
+        let token = format!("{}{}", PERSONAL_ACCESS_TOKEN_PREFIX, generate_token());
+        let expires_at = input
+            .expires_in_days
+            .map(|days| Utc::now() + Duration::days(days as i64));
+        let personal_access_token = sqlx::query_as(
+            r#"
+            insert into personal_access_tokens (user_id, ws_id, name, token_hash, scopes, expires_at)
+            values ($1, $2, $3, $4, $5, $6)
+            returning id, name, scopes, expires_at, last_used_at, created_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(user.ws_id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(&scopes)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedPersonalAccessToken {
+            token,
+            personal_access_token,
+        })
+    }
+
+    /// tokens of the user which are not revoked, newest first
+    pub async fn list_personal_access_tokens(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<PersonalAccessToken>, AppError> {
+        let tokens = sqlx::query_as(
+            r#"
+            select id, name, scopes, expires_at, last_used_at, created_at
+            from personal_access_tokens
+            where user_id = $1 and revoked_at is null
+            order by id desc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    pub async fn revoke_personal_access_token(
+        &self,
+        id: i64,
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        let ret = sqlx::query(
+            r#"
+            update personal_access_tokens set revoked_at = now()
+            where id = $1 and user_id = $2 and revoked_at is null
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!(
+                "personal access token {} not found",
+                id
+            )));
+        }
+        Ok(())
+    }
+
+    /// find the user, in the workspace of the token, and the scopes of an active token, and record when it's used
+    pub async fn verify_personal_access_token(
+        &self,
+        token: &str,
+    ) -> Result<(User, Vec<Scope>), AppError> {
+        let scopes: Option<(i64, Option<i64>, Vec<Scope>)> = sqlx::query_as(
+            r#"
+            update personal_access_tokens set last_used_at = now()
+            where token_hash = $1 and revoked_at is null and (expires_at is null or expires_at > now())
+            returning user_id, ws_id, scopes
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&self.pool)
+        .await?;
+        let (user_id, ws_id, scopes) = scopes.ok_or(AppError::TokenRevoked)?;
+        let mut user = self
+            .find_user_by_id(user_id)
+            .await?
+            .ok_or(AppError::TokenRevoked)?;
+        if let Some(ws_id) = ws_id {
+            user.ws_id = ws_id;
+        }
+        Ok((user, scopes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn personal_access_token_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreatePersonalAccessToken {
+            name: "bot".to_string(),
+            scopes: vec![Scope::ChatsRead, Scope::MessagesRead],
+            expires_in_days: Some(30),
+        };
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let created = state.create_personal_access_token(&user, &input).await?;
+        assert!(created.token.starts_with(PERSONAL_ACCESS_TOKEN_PREFIX));
+
+        let (user, scopes) = state.verify_personal_access_token(&created.token).await?;
+        assert_eq!(user.id, 2);
+        assert_eq!(scopes, vec![Scope::ChatsRead, Scope::MessagesRead]);
+
+        let tokens = state.list_personal_access_tokens(2).await?;
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].last_used_at.is_some());
+
+        // other users can't revoke it
+        let id = created.personal_access_token.id;
+        let res = state.revoke_personal_access_token(id, 3).await;
+        assert!(matches!(res, Err(AppError::NotFound(_))));
+        state.revoke_personal_access_token(id, 2).await?;
+        let res = state.verify_personal_access_token(&created.token).await;
+        assert!(matches!(res, Err(AppError::TokenRevoked)));
+        assert!(state.list_personal_access_tokens(2).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_personal_access_token_without_scope_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut input = CreatePersonalAccessToken {
+            name: "bot".to_string(),
+            scopes: vec![],
+            expires_in_days: None,
+        };
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let res = state.create_personal_access_token(&user, &input).await;
+        assert!(matches!(res, Err(AppError::InvalidInput(_))));
+
+        input.scopes = vec![Scope::ChatsRead];
+        for days in [MAX_EXPIRES_IN_DAYS + 1, u32::MAX] {
+            input.expires_in_days = Some(days);
+            let res = state.create_personal_access_token(&user, &input).await;
+            assert!(matches!(res, Err(AppError::InvalidInput(_))));
+        }
+        input.expires_in_days = Some(MAX_EXPIRES_IN_DAYS);
+        state.create_personal_access_token(&user, &input).await?;
+        Ok(())
+    }
+}
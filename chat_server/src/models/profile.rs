@@ -0,0 +1,328 @@
+use crate::{error::AppError, AppState, ChatFile};
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct Profile {
+    pub id: i64,
+    /// the active workspace
+    pub ws_id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub title: Option<String>,
+    pub timezone: Option<String>,
+    /// url of the avatar file
+    pub avatar: Option<String>,
+    #[schema(value_type=Option<String>)]
+    pub verified_at: Option<DateTime<Utc>>,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// fields not set are kept, an empty string clears the title, timezone or avatar
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateProfile {
+    pub fullname: Option<String>,
+    pub title: Option<String>,
+    /// IANA time zone name, e.g. Asia/Hong_Kong
+    pub timezone: Option<String>,
+    /// url of an image uploaded via /api/upload
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+impl AppState {
+    /// profile of the user, with `ws_id` set to the active workspace
+    pub async fn fetch_profile(&self, user: &User) -> Result<Profile, AppError> {
+        let profile = sqlx::query_as(
+            r#"
+            select id, $2 as ws_id, fullname, email, title, timezone, avatar, verified_at, created_at
+            from users where id = $1
+            "#,
+        )
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} not found", user.id)))?;
+        Ok(profile)
+    }
+
+    /// update the profile, the users sharing a workspace with the user are notified by `profile_updated`
+    pub async fn update_profile(
+        &self,
+        user: &User,
+        input: &UpdateProfile,
+    ) -> Result<Profile, AppError> {
+        let mut profile = self.fetch_profile(user).await?;
+        if let Some(fullname) = &input.fullname {
+            profile.fullname = optional_text(fullname, "fullname", 64)?.ok_or_else(|| {
+                AppError::InvalidInput("fullname must be 1 to 64 characters".to_string())
+            })?;
+        }
+        if let Some(title) = &input.title {
+            profile.title = optional_text(title, "title", 64)?;
+        }
+        if let Some(timezone) = &input.timezone {
+            profile.timezone = optional_text(timezone, "timezone", 64)?;
+            if let Some(timezone) = &profile.timezone {
+                self.validate_timezone(timezone).await?;
+            }
+        }
+        if let Some(avatar) = &input.avatar {
+            profile.avatar = optional_text(avatar, "avatar", 256)?;
+            if let Some(avatar) = &profile.avatar {
+                self.validate_avatar(avatar)?;
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            update users set fullname = $2, title = $3, timezone = $4, avatar = $5
+            where id = $1
+            "#,
+        )
+        .bind(user.id)
+        .bind(&profile.fullname)
+        .bind(&profile.title)
+        .bind(&profile.timezone)
+        .bind(&profile.avatar)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            select pg_notify('profile_updated', json_build_object(
+                'user', json_build_object(
+                    'id', u.id, 'fullname', u.fullname, 'email', u.email,
+                    'title', u.title, 'timezone', u.timezone, 'avatar', u.avatar)
+            )::text)
+            from users u where u.id = $1
+            "#,
+        )
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(profile)
+    }
+
+    /// change the password after confirming the current one, like a signin it's throttled.
+    /// The other sessions of the user are revoked.
+    pub async fn change_password(
+        &self,
+        user: &User,
+        session_id: Option<i64>,
+        input: &ChangePassword,
+    ) -> Result<(), AppError> {
+        self.confirm_password(user, &input.current_password).await?;
+        self.password.validate(&input.new_password)?;
+        let password_hash = self.password.hash(&input.new_password)?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"update users set password_hash = $1 where id = $2"#)
+            .bind(password_hash)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            update refresh_tokens set revoked_at = now()
+            where user_id = $1 and session_id is distinct from $2 and revoked_at is null
+            "#,
+        )
+        .bind(user.id)
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for session in self.list_sessions(user.id, session_id).await? {
+            if !session.current {
+                self.revoke_session(session.id, user.id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// check the password of the user, failures are throttled like a signin
+    pub(crate) async fn confirm_password(
+        &self,
+        user: &User,
+        password: &str,
+    ) -> Result<(), AppError> {
+        self.reserve_signin_attempt(&user.email, None).await?;
+        let password_hash = self.password_hash(user.id).await?;
+        // users created by OIDC have no password, they can set one by resetting it
+        if password_hash.is_empty() || !self.password.verify(password, &password_hash)? {
+            return Err(AppError::IncorrectPassword);
+        }
+        self.reset_signin_failures(&user.email).await
+    }
+
+    /// empty if the user has no password
+    pub(crate) async fn password_hash(&self, user_id: i64) -> Result<String, AppError> {
+        let (password_hash,): (String,) =
+            sqlx::query_as(r#"select password_hash from users where id = $1"#)
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(password_hash)
+    }
+
+    async fn validate_timezone(&self, timezone: &str) -> Result<(), AppError> {
+        let (exists,): (bool,) =
+            sqlx::query_as(r#"select exists(select 1 from pg_timezone_names where name = $1)"#)
+                .bind(timezone)
+                .fetch_one(&self.pool)
+                .await?;
+        if !exists {
+            return Err(AppError::InvalidInput(format!(
+                "unknown timezone: {}",
+                timezone
+            )));
+        }
+        Ok(())
+    }
+
+    /// the avatar must be an uploaded image
+    fn validate_avatar(&self, url: &str) -> Result<(), AppError> {
+        let file = ChatFile::from_str(url)?;
+        let path = file.path(&self.config.server.base_dir);
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        if mime.type_() != mime_guess::mime::IMAGE || !path.exists() {
+            return Err(AppError::ChatFileError(
+                "avatar must be an uploaded image".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// trimmed text, None if it's empty
+fn optional_text(value: &str, field: &str, max: usize) -> Result<Option<String>, AppError> {
+    let value = value.trim();
+    if value.chars().count() > max {
+        return Err(AppError::InvalidInput(format!(
+            "{} must be at most {} characters",
+            field, max
+        )));
+    }
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::SigninUser;
+    use anyhow::Result;
+    use tokio::fs;
+
+    #[tokio::test]
+    async fn update_profile_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(uuid::Uuid::now_v7().to_string());
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.server.base_dir = base_dir.clone()).await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let avatar = ChatFile::new(user.ws_id as _, "me.png", b"png");
+        let path = avatar.path(&base_dir);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(&path, b"png").await?;
+
+        let input = UpdateProfile {
+            fullname: Some(" Zack ".to_string()),
+            title: Some("engineer".to_string()),
+            timezone: Some("Asia/Hong_Kong".to_string()),
+            avatar: Some(avatar.url()),
+        };
+        let profile = state.update_profile(&user, &input).await?;
+        assert_eq!(profile.fullname, "Zack");
+        assert_eq!(profile.timezone.as_deref(), Some("Asia/Hong_Kong"));
+        let users = state.fetch_chat_user_by_ids(2, &[2]).await?;
+        assert_eq!(users[0].avatar, Some(avatar.url()));
+
+        // only the title is cleared
+        let input = UpdateProfile {
+            title: Some("".to_string()),
+            ..Default::default()
+        };
+        let profile = state.update_profile(&user, &input).await?;
+        assert_eq!(profile.title, None);
+        assert_eq!(profile.avatar, Some(avatar.url()));
+
+        let input = UpdateProfile {
+            timezone: Some("Mars/Olympus_Mons".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_profile(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        let input = UpdateProfile {
+            avatar: Some(ChatFile::new(2, "a.txt", b"txt").url()),
+            ..Default::default()
+        };
+        let ret = state.update_profile(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::ChatFileError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_profile_should_work_in_large_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // more members than a pg_notify payload can list
+        sqlx::query(
+            r#"
+            with users as (
+                insert into users (ws_id, email, fullname, password_hash)
+                select 2, 'user' || i || '@large.workspace', 'user' || i, ''
+                from generate_series(1, 1000) i
+                returning id
+            )
+            insert into workspace_members (ws_id, user_id) select 2, id from users
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        let user = state.find_user_by_id(2).await?.unwrap();
+        let input = UpdateProfile {
+            title: Some("engineer".to_string()),
+            ..Default::default()
+        };
+        let profile = state.update_profile(&user, &input).await?;
+        assert_eq!(profile.title.as_deref(), Some("engineer"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn change_password_should_confirm_current_one() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(6).await?.unwrap();
+        let current = state.create_session(user.id, &Default::default()).await?;
+        let other = state.create_session(user.id, &Default::default()).await?;
+
+        let mut input = ChangePassword {
+            current_password: "wrong-password".to_string(),
+            new_password: "hunter42".to_string(),
+        };
+        let ret = state.change_password(&user, Some(current), &input).await;
+        assert!(matches!(ret, Err(AppError::IncorrectPassword)));
+
+        // the failure is throttled like a signin
+        state.reset_signin_failures(&user.email).await?;
+        input.current_password = "Jiajia520".to_string();
+        state.change_password(&user, Some(current), &input).await?;
+        let signin = SigninUser::new(&user.email, "hunter42");
+        assert!(state.verify_user(signin).await?.is_some());
+        assert!(state.touch_session(current).await?);
+        assert!(!state.touch_session(other).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{error::AppError, AppState};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::{convert::Infallible, net::SocketAddr};
+use utoipa::ToSchema;
+
+/// clients may name the device by this header, e.g. "zack's iphone"
+pub const DEVICE_NAME_HEADER: &str = "x-device-name";
+
+/// last_seen_at is updated at most once per minute
+const LAST_SEEN_INTERVAL: i64 = 60;
+
+/// where the signin comes from, it's extracted from the request headers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct Session {
+    pub id: i64,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// the session of the token used for this request
+    pub current: bool,
+    #[schema(value_type=String)]
+    pub last_seen_at: DateTime<Utc>,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SessionInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str, max: usize| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().chars().take(max).collect::<String>())
+                .filter(|v| !v.is_empty())
+        };
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        Ok(Self {
+            device_name: header(DEVICE_NAME_HEADER, 64),
+            user_agent: header(USER_AGENT.as_str(), 256),
+            ip,
+        })
+    }
+}
+
+impl AppState {
+    /// the session starts in the default workspace of the user
+    pub async fn create_session(&self, user_id: i64, info: &SessionInfo) -> Result<i64, AppError> {
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            insert into sessions (user_id, ws_id, device_name, user_agent, ip)
+            select $1, ws_id, $2, $3, $4 from users where id = $1
+            returning id
+            "#,
+        )
+        .bind(user_id)
+        .bind(&info.device_name)
+        .bind(&info.user_agent)
+        .bind(&info.ip)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// list the sessions of the user which are not revoked, the most recently seen first
+    pub async fn list_sessions(
+        &self,
+        user_id: i64,
+        current: Option<i64>,
+    ) -> Result<Vec<Session>, AppError> {
+        let sessions = sqlx::query_as(
+            r#"
+            select id, device_name, user_agent, ip, (id = $2) is true as current, last_seen_at, created_at
+            from sessions
+            where user_id = $1 and revoked_at is null
+            order by last_seen_at desc, id desc
+            "#,
+        )
+        .bind(user_id)
+        .bind(current)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    /// revoke the session and its refresh tokens, the access tokens of it are rejected from now on.
+    /// notify_server closes the SSE connections of the session on the `session_revoked` notification.
+    pub async fn revoke_session(&self, id: i64, user_id: i64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            update sessions set revoked_at = now()
+            where id = $1 and user_id = $2 and revoked_at is null
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("session {} not found", id)));
+        }
+        sqlx::query(
+            r#"update refresh_tokens set revoked_at = now() where session_id = $1 and revoked_at is null"#,
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            select pg_notify('session_revoked', json_build_object(
+                'user_id', $1::bigint, 'session_id', $2::bigint)::text)
+            "#,
+        )
+        .bind(user_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// the active workspace of the session
+    pub async fn session_workspace(&self, id: i64) -> Result<Option<i64>, AppError> {
+        let rec: Option<(Option<i64>,)> =
+            sqlx::query_as("select ws_id from sessions where id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(rec.and_then(|(ws_id,)| ws_id))
+    }
+
+    /// check the session is not revoked and update its last_seen_at,
+    /// the write is skipped when it was seen within LAST_SEEN_INTERVAL
+    pub async fn touch_session(&self, id: i64) -> Result<bool, AppError> {
+        let rec: Option<(bool, bool)> = sqlx::query_as(
+            r#"
+            select revoked_at is null, last_seen_at < now() - make_interval(secs => $2)
+            from sessions where id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(LAST_SEEN_INTERVAL as f64)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((active, stale)) = rec else {
+            return Ok(false);
+        };
+        if active && stale {
+            sqlx::query("update sessions set last_seen_at = now() where id = $1")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn session_revoke_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let info = SessionInfo {
+            device_name: Some("laptop".to_string()),
+            ..Default::default()
+        };
+        let first = state.create_session(2, &info).await?;
+        let second = state.create_session(2, &SessionInfo::default()).await?;
+        let token = state.create_refresh_token(2, Some(first)).await?;
+
+        let sessions = state.list_sessions(2, Some(second)).await?;
+        assert_eq!(sessions.len(), 2);
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(current.id, second);
+        assert!(state.touch_session(first).await?);
+
+        // other user can't revoke it
+        let ret = state.revoke_session(first, 3).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.revoke_session(first, 2).await?;
+        assert!(!state.touch_session(first).await?);
+        let ret = state.rotate_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken(_))));
+        let sessions = state.list_sessions(2, None).await?;
+        assert_eq!(sessions.len(), 1);
+        assert!(!sessions[0].current);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn touch_session_should_throttle_last_seen() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let id = state.create_session(2, &SessionInfo::default()).await?;
+        let last_seen = |state: &AppState| {
+            let pool = state.pool.clone();
+            async move {
+                let (at,): (DateTime<Utc>,) =
+                    sqlx::query_as("select last_seen_at from sessions where id = $1")
+                        .bind(id)
+                        .fetch_one(&pool)
+                        .await?;
+                anyhow::Ok(at)
+            }
+        };
+
+        let seen = last_seen(&state).await?;
+        assert!(state.touch_session(id).await?);
+        assert_eq!(last_seen(&state).await?, seen);
+
+        sqlx::query(
+            "update sessions set last_seen_at = now() - interval '2 minutes' where id = $1",
+        )
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+        let stale = last_seen(&state).await?;
+        assert!(state.touch_session(id).await?);
+        assert!(last_seen(&state).await? > stale);
+        assert!(!state.touch_session(-1).await?);
+        Ok(())
+    }
+}
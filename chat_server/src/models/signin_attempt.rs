@@ -0,0 +1,231 @@
+use crate::{error::AppError, AppState};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::FromRow;
+use std::net::IpAddr;
+
+const ACCOUNT: &str = "account";
+const IP: &str = "ip";
+
+#[derive(Debug, FromRow)]
+struct SigninAttempt {
+    failures: i32,
+    last_failure_at: DateTime<Utc>,
+    next_attempt_at: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// reserve a signin attempt before verifying the password, argon2 is expensive.
+    /// It's rejected if the account or IP is throttled, otherwise it counts as a failure until
+    /// `record_signin_success`, so concurrent attempts can't pass the throttle together.
+    /// The account backs off exponentially and both are locked at the threshold.
+    pub async fn reserve_signin_attempt(
+        &self,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), AppError> {
+        let throttle = &self.config.auth.signin_throttle;
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+        // the rows are locked in the same order by every attempt
+        for (kind, subject) in signin_subjects(email, ip) {
+            let attempt: SigninAttempt = sqlx::query_as(
+                r#"
+                insert into signin_attempts (kind, subject) values ($1, $2)
+                on conflict (kind, subject) do update set kind = excluded.kind
+                returning failures, last_failure_at, next_attempt_at, locked_until
+                "#,
+            )
+            .bind(kind)
+            .bind(&subject)
+            .fetch_one(&mut *tx)
+            .await?;
+            if let Some(until) = attempt.locked_until.filter(|t| *t > now) {
+                let secs = retry_after(until, now);
+                return Err(match kind {
+                    ACCOUNT => AppError::AccountLocked(secs),
+                    _ => AppError::TooManyAttempts(secs),
+                });
+            }
+            if let Some(next) = attempt.next_attempt_at.filter(|t| *t > now) {
+                return Err(AppError::TooManyAttempts(retry_after(next, now)));
+            }
+
+            // failures older than the lockout window are forgotten
+            let window = Duration::seconds(throttle.lockout_seconds as i64);
+            let failures = if attempt.last_failure_at > now - window {
+                attempt.failures as u32 + 1
+            } else {
+                1
+            };
+            let (threshold, next_attempt_at) = match kind {
+                ACCOUNT => {
+                    let exp = (failures - 1).min(16);
+                    let backoff =
+                        (throttle.backoff_base_seconds << exp).min(throttle.backoff_max_seconds);
+                    (
+                        throttle.account_lockout_threshold,
+                        Some(now + Duration::seconds(backoff as i64)),
+                    )
+                }
+                // many users may share an IP, so it's only locked at the threshold
+                _ => (throttle.ip_lockout_threshold, None),
+            };
+            let locked_until = (failures >= threshold).then(|| now + window);
+            sqlx::query(
+                r#"
+                update signin_attempts set
+                    failures = $3, last_failure_at = $4, next_attempt_at = $5, locked_until = $6
+                where kind = $1 and subject = $2
+                "#,
+            )
+            .bind(kind)
+            .bind(&subject)
+            .bind(failures as i32)
+            .bind(now)
+            .bind(next_attempt_at)
+            .bind(locked_until)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// the password is verified: forget the failures of the account, and don't count the
+    /// reserved attempt against the IP
+    pub async fn record_signin_success(
+        &self,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), AppError> {
+        self.reset_signin_failures(email).await?;
+        if let Some(ip) = ip {
+            sqlx::query(
+                r#"
+                update signin_attempts set
+                    failures = greatest(failures - 1, 0),
+                    locked_until = case when failures - 1 >= $3 then locked_until end
+                where kind = $1 and subject = $2
+                "#,
+            )
+            .bind(IP)
+            .bind(ip.to_string())
+            .bind(self.config.auth.signin_throttle.ip_lockout_threshold as i32)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// forget the failures of the account, after a successful signin or by an admin
+    pub async fn reset_signin_failures(&self, email: &str) -> Result<(), AppError> {
+        sqlx::query(r#"delete from signin_attempts where kind = $1 and subject = $2"#)
+            .bind(ACCOUNT)
+            .bind(email.to_lowercase())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn signin_subjects(email: &str, ip: Option<IpAddr>) -> Vec<(&'static str, String)> {
+    let mut subjects = vec![(ACCOUNT, email.to_lowercase())];
+    if let Some(ip) = ip {
+        subjects.push((IP, ip.to_string()));
+    }
+    subjects
+}
+
+fn retry_after(until: DateTime<Utc>, now: DateTime<Utc>) -> u64 {
+    ((until - now).num_milliseconds() as u64)
+        .div_ceil(1000)
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn signin_failures_should_back_off_and_lock() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ip: IpAddr = "10.0.0.1".parse()?;
+        state
+            .reserve_signin_attempt("Zack@email.com", Some(ip))
+            .await?;
+        let res = state.reserve_signin_attempt("zack@email.com", None).await;
+        assert!(matches!(res, Err(AppError::TooManyAttempts(1))));
+        // another account from the same IP is not backed off
+        state
+            .reserve_signin_attempt("bency@email.com", Some(ip))
+            .await?;
+
+        let threshold = state.config.auth.signin_throttle.account_lockout_threshold;
+        for _ in 1..threshold {
+            skip_backoff(&state).await?;
+            state.reserve_signin_attempt("zack@email.com", None).await?;
+        }
+        let res = state.reserve_signin_attempt("zack@email.com", None).await;
+        assert!(matches!(res, Err(AppError::AccountLocked(_))));
+
+        state.reset_signin_failures("zack@email.com").await?;
+        state
+            .reserve_signin_attempt("zack@email.com", Some(ip))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_failures_should_lock_ip() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ip: IpAddr = "10.0.0.2".parse()?;
+        let threshold = state.config.auth.signin_throttle.ip_lockout_threshold;
+        for i in 0..threshold - 1 {
+            state
+                .reserve_signin_attempt(&format!("user{}@email.com", i), Some(ip))
+                .await?;
+        }
+        // a successful signin is not counted
+        state
+            .reserve_signin_attempt("zack@email.com", Some(ip))
+            .await?;
+        state
+            .record_signin_success("zack@email.com", Some(ip))
+            .await?;
+        state
+            .reserve_signin_attempt("bency@email.com", Some(ip))
+            .await?;
+        let res = state
+            .reserve_signin_attempt("zack@email.com", Some(ip))
+            .await;
+        assert!(matches!(res, Err(AppError::TooManyAttempts(_))));
+        state.reserve_signin_attempt("zack@email.com", None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_signin_attempts_should_be_throttled() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let attempts = (0..10).map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move { state.reserve_signin_attempt("zack@email.com", None).await })
+        });
+        let mut allowed = 0;
+        for attempt in attempts {
+            if attempt.await?.is_ok() {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 1);
+        Ok(())
+    }
+
+    async fn skip_backoff(state: &AppState) -> Result<()> {
+        sqlx::query("update signin_attempts set next_attempt_at = null")
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+}
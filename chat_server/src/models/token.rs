@@ -0,0 +1,186 @@
+use crate::{error::AppError, AppState};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+
+/// refresh token is valid for 30 days, it's rotated every time it's used
+const REFRESH_TOKEN_DURATION: i64 = 60 * 60 * 24 * 30;
+
+impl AppState {
+    /// create a new refresh token for user, only the sha256 hash is stored in db
+    pub async fn create_refresh_token(
+        &self,
+        user_id: i64,
+        session_id: Option<i64>,
+    ) -> Result<String, AppError> {
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION);
+        sqlx::query(
+            r#"
+            insert into refresh_tokens (user_id, session_id, token_hash, expires_at)
+            values ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(session_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    /// revoke the refresh token and return its owner and session with a new refresh token,
+    /// `ws_id` of the owner is the active workspace of the session.
+    /// if an already revoked token is presented, it may have been leaked,
+    /// so all the refresh tokens of the user are revoked.
+    pub async fn rotate_refresh_token(
+        &self,
+        token: &str,
+    ) -> Result<(User, Option<i64>, String), AppError> {
+        let token_hash = hash_token(token);
+        let rec: Option<(i64, Option<i64>)> = sqlx::query_as(
+            r#"
+            update refresh_tokens set revoked_at = now()
+            where token_hash = $1 and revoked_at is null and expires_at > now()
+            returning user_id, session_id
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((user_id, session_id)) = rec else {
+            let reused: Option<(i64,)> = sqlx::query_as(
+                r#"select user_id from refresh_tokens where token_hash = $1 and revoked_at is not null"#,
+            )
+            .bind(&token_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+            if let Some((user_id,)) = reused {
+                self.revoke_all_refresh_tokens(user_id).await?;
+            }
+            return Err(AppError::InvalidRefreshToken(
+                "refresh token is expired or revoked".to_string(),
+            ));
+        };
+
+        let mut user = self
+            .find_user_by_id(user_id)
+            .await?
+            .ok_or_else(|| AppError::InvalidRefreshToken("user not found".to_string()))?;
+        if let Some(session_id) = session_id {
+            if !self.touch_session(session_id).await? {
+                return Err(AppError::InvalidRefreshToken(
+                    "session is revoked".to_string(),
+                ));
+            }
+            // stay in the workspace switched to by the session
+            if let Some(ws_id) = self.session_workspace(session_id).await? {
+                user.ws_id = ws_id;
+            }
+        }
+        let token = self.create_refresh_token(user_id, session_id).await?;
+        Ok((user, session_id, token))
+    }
+
+    /// revoke a refresh token of the user, used by sign out
+    pub async fn revoke_refresh_token(&self, token: &str, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            update refresh_tokens set revoked_at = now()
+            where token_hash = $1 and user_id = $2 and revoked_at is null
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn revoke_all_refresh_tokens(&self, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"update refresh_tokens set revoked_at = now() where user_id = $1 and revoked_at is null"#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// revoke an access token by its jwt id(jti), it's kept until the token expires
+    pub async fn revoke_access_token(
+        &self,
+        jti: &str,
+        user_id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            insert into revoked_tokens (jti, user_id, expires_at) values ($1, $2, $3)
+            on conflict (jti) do nothing
+            "#,
+        )
+        .bind(jti)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError> {
+        let rec = sqlx::query(r#"select 1 from revoked_tokens where jti = $1"#)
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(rec.is_some())
+    }
+}
+
+/// random 256 bits token, hex encoded
+pub(crate) fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn refresh_token_rotate_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.create_refresh_token(1, None).await?;
+        let (user, session_id, new_token) = state.rotate_refresh_token(&token).await?;
+        assert_eq!(user.id, 1);
+        assert_eq!(session_id, None);
+        assert_ne!(token, new_token);
+
+        // old token can't be used again, and reusing it revokes the new one too
+        let res = state.rotate_refresh_token(&token).await;
+        assert!(matches!(res, Err(AppError::InvalidRefreshToken(_))));
+        let res = state.rotate_refresh_token(&new_token).await;
+        assert!(matches!(res, Err(AppError::InvalidRefreshToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_access_token_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(!state.is_token_revoked("jti").await?);
+        let expires_at = Utc::now() + Duration::seconds(60);
+        state.revoke_access_token("jti", 1, expires_at).await?;
+        state.revoke_access_token("jti", 1, expires_at).await?;
+        assert!(state.is_token_revoked("jti").await?);
+        Ok(())
+    }
+}
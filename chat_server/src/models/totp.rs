@@ -0,0 +1,345 @@
+use super::token::{generate_token, hash_token};
+use crate::{error::AppError, AppState};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::User;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+use utoipa::ToSchema;
+
+const TOTP_ISSUER: &str = "chat";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// the challenge must be completed in 5 minutes
+const CHALLENGE_DURATION: i64 = 60 * 5;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrollment {
+    /// base32 encoded secret
+    pub secret: String,
+    /// otpauth:// uri, can be rendered as a QR code for authenticator apps
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TotpCode {
+    /// TOTP code, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// each code can be used once instead of a TOTP code, they are only shown once
+    pub recovery_codes: Vec<String>,
+}
+
+/// returned by signin when the user has TOTP enabled
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaChallenge {
+    pub challenge: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SigninTotp {
+    pub challenge: String,
+    /// TOTP code, or a recovery code
+    pub code: String,
+}
+
+impl AppState {
+    pub async fn is_totp_enabled(&self, user_id: i64) -> Result<bool, AppError> {
+        let rec =
+            sqlx::query(r#"select 1 from user_totp where user_id = $1 and enabled_at is not null"#)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(rec.is_some())
+    }
+
+    /// generate a new TOTP secret for user, it's enabled once a code is confirmed
+    pub async fn enroll_totp(&self, user: &User) -> Result<TotpEnrollment, AppError> {
+        let Secret::Encoded(secret) = Secret::generate_secret().to_encoded() else {
+            unreachable!("secret should be encoded");
+        };
+        let totp = build_totp(&secret, &user.email)?;
+        let rec = sqlx::query(
+            r#"
+            insert into user_totp (user_id, secret) values ($1, $2)
+            on conflict (user_id) do update set secret = excluded.secret, last_used_step = 0
+            where user_totp.enabled_at is null
+            "#,
+        )
+        .bind(user.id)
+        .bind(&secret)
+        .execute(&self.pool)
+        .await?;
+        if rec.rows_affected() == 0 {
+            return Err(AppError::TwoFactorError(
+                "TOTP is already enabled".to_string(),
+            ));
+        }
+
+        Ok(TotpEnrollment {
+            otpauth_uri: totp.get_url(),
+            secret,
+        })
+    }
+
+    /// enable TOTP with the first code from the authenticator, and generate recovery codes
+    pub async fn confirm_totp(&self, user: &User, code: &str) -> Result<RecoveryCodes, AppError> {
+        let secret: Option<(String,)> = sqlx::query_as(
+            r#"select secret from user_totp where user_id = $1 and enabled_at is null"#,
+        )
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((secret,)) = secret else {
+            return Err(AppError::TwoFactorError(
+                "TOTP is not enrolled or already enabled".to_string(),
+            ));
+        };
+        let Some(step) = match_step(&build_totp(&secret, &user.email)?, code) else {
+            return Err(AppError::InvalidTotpCode);
+        };
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect();
+        let code_hashes: Vec<String> = recovery_codes.iter().map(|c| hash_token(c)).collect();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"update user_totp set enabled_at = now(), last_used_step = $2 where user_id = $1"#,
+        )
+        .bind(user.id)
+        .bind(step as i64)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(r#"delete from totp_recovery_codes where user_id = $1"#)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"insert into totp_recovery_codes (user_id, code_hash) select $1, unnest($2::char(64)[])"#,
+        )
+        .bind(user.id)
+        .bind(&code_hashes)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(RecoveryCodes { recovery_codes })
+    }
+
+    /// disable TOTP, a valid code is required
+    pub async fn disable_totp(&self, user_id: i64, code: &str) -> Result<(), AppError> {
+        if !self.verify_second_factor(user_id, code).await? {
+            return Err(AppError::InvalidTotpCode);
+        }
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"delete from totp_recovery_codes where user_id = $1"#)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"delete from user_totp where user_id = $1"#)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// create a short-lived challenge after the password is verified
+    pub async fn create_mfa_challenge(&self, user_id: i64) -> Result<MfaChallenge, AppError> {
+        let challenge = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(CHALLENGE_DURATION);
+        sqlx::query(
+            r#"insert into mfa_challenges (user_id, token_hash, expires_at) values ($1, $2, $3)"#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&challenge))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(MfaChallenge { challenge })
+    }
+
+    /// complete the challenge with a TOTP or recovery code, and return the user to sign in.
+    /// a challenge can be tried at most 5 times.
+    pub async fn complete_mfa_challenge(&self, input: &SigninTotp) -> Result<User, AppError> {
+        let user_id: Option<(i64,)> = sqlx::query_as(
+            r#"
+            update mfa_challenges set attempts = attempts + 1
+            where token_hash = $1 and used_at is null and expires_at > now() and attempts < $2
+            returning user_id
+            "#,
+        )
+        .bind(hash_token(&input.challenge))
+        .bind(MAX_CHALLENGE_ATTEMPTS)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((user_id,)) = user_id else {
+            return Err(AppError::InvalidToken(
+                "signin challenge is invalid or expired".to_string(),
+            ));
+        };
+
+        if !self.verify_second_factor(user_id, &input.code).await? {
+            return Err(AppError::InvalidTotpCode);
+        }
+        sqlx::query(r#"update mfa_challenges set used_at = now() where token_hash = $1"#)
+            .bind(hash_token(&input.challenge))
+            .execute(&self.pool)
+            .await?;
+
+        self.find_user_by_id(user_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("user {} not found", user_id)))
+    }
+
+    /// check a TOTP code or a recovery code of user with TOTP enabled, every code can only be used once
+    async fn verify_second_factor(&self, user_id: i64, code: &str) -> Result<bool, AppError> {
+        let rec: Option<(String, String)> = sqlx::query_as(
+            r#"
+            select t.secret, u.email from user_totp t join users u on t.user_id = u.id
+            where t.user_id = $1 and t.enabled_at is not null
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((secret, email)) = rec else {
+            return Ok(false);
+        };
+
+        let code = code.trim();
+        if let Some(step) = match_step(&build_totp(&secret, &email)?, code) {
+            let ret = sqlx::query(
+                r#"update user_totp set last_used_step = $2 where user_id = $1 and last_used_step < $2"#,
+            )
+            .bind(user_id)
+            .bind(step as i64)
+            .execute(&self.pool)
+            .await?;
+            return Ok(ret.rows_affected() == 1);
+        }
+
+        let ret = sqlx::query(
+            r#"
+            update totp_recovery_codes set used_at = now()
+            where user_id = $1 and code_hash = $2 and used_at is null
+            "#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&code.to_lowercase()))
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected() == 1)
+    }
+}
+
+fn build_totp(secret: &str, account: &str) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| AppError::TwoFactorError(format!("invalid TOTP secret: {:?}", e)))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account.to_string(),
+    )
+    .map_err(|e| AppError::TwoFactorError(format!("invalid TOTP: {:?}", e)))
+}
+
+/// find the time step of the code, allowing one step of clock drift
+fn match_step(totp: &TOTP, code: &str) -> Option<u64> {
+    let step = Utc::now().timestamp() as u64 / TOTP_STEP;
+    [step - 1, step, step + 1]
+        .into_iter()
+        .find(|s| totp.generate(s * TOTP_STEP) == code)
+}
+
+/// e.g. 3f9a1-0c2d4
+fn generate_recovery_code() -> String {
+    let mut buf = [0u8; 5];
+    OsRng.fill_bytes(&mut buf);
+    let code = hex::encode(buf);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn current_code(secret: &str, account: &str) -> String {
+        build_totp(secret, account)
+            .unwrap()
+            .generate_current()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn totp_enroll_and_challenge_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("zack@email.com").await?.unwrap();
+        let enrollment = state.enroll_totp(&user).await?;
+        assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/chat:"));
+        assert!(!state.is_totp_enabled(user.id).await?);
+
+        let res = state.confirm_totp(&user, "000000x").await;
+        assert!(matches!(res, Err(AppError::InvalidTotpCode)));
+        let code = current_code(&enrollment.secret, &user.email);
+        let codes = state.confirm_totp(&user, &code).await?;
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODE_COUNT);
+        assert!(state.is_totp_enabled(user.id).await?);
+
+        // enrolled again is not allowed
+        let res = state.enroll_totp(&user).await;
+        assert!(matches!(res, Err(AppError::TwoFactorError(_))));
+
+        // the code used to confirm can't be reused, recovery code works once
+        let challenge = state.create_mfa_challenge(user.id).await?;
+        let input = SigninTotp {
+            challenge: challenge.challenge.clone(),
+            code,
+        };
+        let res = state.complete_mfa_challenge(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidTotpCode)));
+        let input = SigninTotp {
+            challenge: challenge.challenge.clone(),
+            code: codes.recovery_codes[0].clone(),
+        };
+        let signed_in = state.complete_mfa_challenge(&input).await?;
+        assert_eq!(signed_in.id, user.id);
+        let res = state.complete_mfa_challenge(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidToken(_))));
+
+        state
+            .disable_totp(user.id, &codes.recovery_codes[1])
+            .await?;
+        assert!(!state.is_totp_enabled(user.id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mfa_challenge_should_limit_attempts() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("zack@email.com").await?.unwrap();
+        let challenge = state.create_mfa_challenge(user.id).await?;
+        let input = SigninTotp {
+            challenge: challenge.challenge,
+            code: "000000".to_string(),
+        };
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            let res = state.complete_mfa_challenge(&input).await;
+            assert!(matches!(res, Err(AppError::InvalidTotpCode)));
+        }
+        let res = state.complete_mfa_challenge(&input).await;
+        assert!(matches!(res, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{error::AppError, AppState};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// a user's typing signals in a chat are fanned out at most once in it
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+/// a typing signal expires after it unless it's refreshed, the members are told the user stopped
+pub const TYPING_TTL: Duration = Duration::from_secs(6);
+
+/// the last fanned out typing signal of (chat_id, user_id)
+#[derive(Debug, Default)]
+pub struct TypingTracker(Mutex<HashMap<(i64, i64), Instant>>);
+
+impl TypingTracker {
+    /// false if a signal was fanned out within `TYPING_INTERVAL`
+    fn start(&self, key: (i64, i64), now: Instant) -> bool {
+        let mut signals = self.0.lock().expect("typing lock poisoned");
+        match signals.get(&key) {
+            Some(last) if now.saturating_duration_since(*last) < TYPING_INTERVAL => false,
+            _ => {
+                signals.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// true if the signal sent at `since` is the last one, it's removed then
+    fn expire(&self, key: (i64, i64), since: Instant) -> bool {
+        let mut signals = self.0.lock().expect("typing lock poisoned");
+        if signals.get(&key) == Some(&since) {
+            signals.remove(&key);
+            return true;
+        }
+        false
+    }
+}
+
+impl AppState {
+    /// tell the other members of the chat that the user is typing, nothing is stored.
+    /// Returns false if it's rate limited.
+    pub async fn signal_typing(&self, chat_id: i64, user_id: i64) -> Result<bool, AppError> {
+        let key = (chat_id, user_id);
+        let now = Instant::now();
+        if !self.typing.start(key, now) {
+            return Ok(false);
+        }
+        self.notify_typing(chat_id, user_id, true).await?;
+
+        let state = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(TYPING_TTL).await;
+            if state.typing.expire(key, now) {
+                if let Err(e) = state.notify_typing(chat_id, user_id, false).await {
+                    tracing::warn!("failed to expire typing of user {}: {:?}", user_id, e);
+                }
+            }
+        });
+        Ok(true)
+    }
+
+    async fn notify_typing(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        typing: bool,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            select pg_notify('chat_typing', json_build_object(
+                'chat_id', $1::bigint, 'user_id', $2::bigint, 'typing', $3)::text)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(typing)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_should_be_rate_limited_and_expire() {
+        let tracker = TypingTracker::default();
+        let now = Instant::now();
+        assert!(tracker.start((1, 2), now));
+        assert!(!tracker.start((1, 2), now + TYPING_INTERVAL / 2));
+        // other users and chats are not limited
+        assert!(tracker.start((1, 3), now));
+        assert!(tracker.start((2, 2), now));
+
+        let later = now + TYPING_INTERVAL;
+        assert!(tracker.start((1, 2), later));
+        // the first signal is refreshed, so only the last one expires
+        assert!(!tracker.expire((1, 2), now));
+        assert!(tracker.expire((1, 2), later));
+        assert!(!tracker.expire((1, 2), later));
+    }
+
+    #[tokio::test]
+    async fn signal_typing_should_be_coalesced() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.signal_typing(2, 2).await?);
+        assert!(!state.signal_typing(2, 2).await?);
+        assert!(state.signal_typing(2, 3).await?);
+        Ok(())
+    }
+}
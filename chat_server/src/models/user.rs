@@ -1,11 +1,9 @@
 use crate::{AppError, AppState, ChatUser};
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
-};
-use chat_core::User;
+use chat_core::{User, WorkSpace, WorkspaceRole};
 use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
 use std::mem;
+use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams)]
@@ -14,6 +12,9 @@ pub struct CreateUser {
     pub fullname: String,
     pub password: String,
     pub workspace: String,
+    /// invite code, required to join an existing workspace
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, IntoParams)]
@@ -44,6 +45,7 @@ impl CreateUser {
             fullname: fullname.into(),
             password: password.into(),
             workspace: ws_name.into(),
+            invite: None,
         }
     }
 }
@@ -51,18 +53,36 @@ impl CreateUser {
 #[allow(unused)]
 impl AppState {
     pub async fn fetch_chat_user_all(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
-        let recs = sqlx::query_as(r#"select id, fullname, email from users where ws_id = $1"#)
-            .bind(ws_id as i64)
-            .fetch_all(&self.pool)
-            .await?;
+        let recs = sqlx::query_as(
+            r#"
+            select u.id, u.fullname, u.email, u.title, u.timezone, u.avatar, m.role
+            from workspace_members m join users u on m.user_id = u.id
+            where m.ws_id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
         Ok(recs)
     }
 
-    pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
-        let recs = sqlx::query_as(r#"select id, fullname, email from users where id = any($1)"#)
-            .bind(ids)
-            .fetch_all(&self.pool)
-            .await?;
+    /// the members of the workspace with the ids
+    pub async fn fetch_chat_user_by_ids(
+        &self,
+        ws_id: u64,
+        ids: &[i64],
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let recs = sqlx::query_as(
+            r#"
+            select u.id, u.fullname, u.email, u.title, u.timezone, u.avatar, m.role
+            from workspace_members m join users u on m.user_id = u.id
+            where m.ws_id = $1 and m.user_id = any($2)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
         Ok(recs)
     }
 
@@ -71,7 +91,7 @@ impl AppState {
         // .fetch_optional(pool).await?;
 
         let rec = sqlx::query_as(
-            r#"select id, ws_id, fullname,email, created_at from users where email = $1"#,
+            r#"select id, ws_id, fullname, email, verified_at, created_at from users where email = $1"#,
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -80,38 +100,94 @@ impl AppState {
         Ok(rec)
     }
 
-    // TODO: use trancaction for workspace creation
+    pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
+        let rec = sqlx::query_as(
+            r#"select id, ws_id, fullname, email, verified_at, created_at from users where id = $1"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// sign up in one transaction, a new workspace is owned by the user and starts with a `general` channel.
+    /// an existing workspace can only be joined with a valid invite. The verification token is
+    /// created with the user and mailed after the commit.
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
         // 插入用户时，检查email是否已经存在，如果存在表示已经注册，则返回错误
         let user = self.find_user_by_email(&input.email).await?;
         if let Some(user) = user {
             return Err(AppError::EmailAlreadyExists(user.email));
         }
-        let password_hash = hash_password(&input.password)?;
+        self.password.validate(&input.password)?;
+        let password_hash = self.password.hash(&input.password)?;
 
+        let mut tx = self.pool.begin().await?;
         // 插入用户时，需要先判断workspace是否存在，如果不存在则创建
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 1).await?,
+        // users.ws_id references the workspace, so it's owned by the super user until the user is created
+        let (ws, created) = match self.find_workspace_by_name(&input.workspace).await? {
+            Some(ws) => {
+                let code = input.invite.as_deref().ok_or_else(|| {
+                    AppError::InvalidInvite("an invite is required to join the workspace".into())
+                })?;
+                self.consume_invite(code, ws.id, &input.email, &mut *tx)
+                    .await?;
+                (ws, false)
+            }
+            None => (
+                self.create_workspace(&input.workspace, 1, &mut *tx).await?,
+                true,
+            ),
         };
 
         // 这里需要通过workspace的name去找id然后插入
         let user: User = sqlx::query_as(
-            r#"insert into users (fullname, email, password_hash, ws_id) values ($1, $2, $3, $4) returning id, ws_id, fullname, email, created_at"#
+            r#"insert into users (fullname, email, password_hash, ws_id) values ($1, $2, $3, $4) returning id, ws_id, fullname, email, verified_at, created_at"#
         )
         .bind(&input.fullname)
         .bind(&input.email)
         .bind(password_hash)
         .bind(ws.id)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
 
+        self.onboard_user(&ws, created, user.id, &mut tx).await?;
+        let token = self.create_email_verification(user.id, &mut tx).await?;
+        tx.commit().await?;
+
+        // the user can ask for another mail, the signup doesn't fail with it
+        if let Err(e) = self.mail_email_verification(&user, &token).await {
+            warn!(
+                "failed to mail the verification token to user {}: {:?}",
+                user.id, e
+            );
+        }
         Ok(user)
     }
 
+    /// add a new user to its workspace, a workspace created for it is owned by it
+    /// and starts with a `general` channel
+    pub(crate) async fn onboard_user(
+        &self,
+        ws: &WorkSpace,
+        created: bool,
+        user_id: i64,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), AppError> {
+        self.add_workspace_member(ws.id, user_id, WorkspaceRole::Member, &mut **tx)
+            .await?;
+        if created {
+            self.update_workspace_owner(ws, user_id as u64, &mut **tx)
+                .await?;
+            self.create_general_channel(ws.id, user_id, tx).await?;
+        }
+        Ok(())
+    }
+
     pub async fn verify_user(&self, input: SigninUser) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            r#"select id, ws_id, fullname, email, password_hash,created_at from users where email = $1"#,
+            r#"select id, ws_id, fullname, email, password_hash, verified_at, created_at from users where email = $1"#,
         )
         .bind(input.email)
         .fetch_optional(&self.pool)
@@ -120,8 +196,16 @@ impl AppState {
         match user {
             Some(mut user) => {
                 let password_hash = mem::take(&mut user.password_hash).unwrap_or_default();
-                let is_valid = verify_password(&input.password, &password_hash)?;
+                // users created by OIDC have no password
+                if password_hash.is_empty() {
+                    return Ok(None);
+                }
+                let is_valid = self.password.verify(&input.password, &password_hash)?;
                 if is_valid {
+                    if self.password.needs_rehash(&password_hash) {
+                        self.rehash_password(user.id, &input.password, &password_hash)
+                            .await?;
+                    }
                     Ok(Some(user))
                 } else {
                     Ok(None)
@@ -131,6 +215,23 @@ impl AppState {
         }
     }
 
+    /// upgrade the stored hash to the current argon2 parameters, unless it has been changed meanwhile
+    async fn rehash_password(
+        &self,
+        user_id: i64,
+        password: &str,
+        old_hash: &str,
+    ) -> Result<(), AppError> {
+        let password_hash = self.password.hash(password)?;
+        sqlx::query(r#"update users set password_hash = $1 where id = $2 and password_hash = $3"#)
+            .bind(password_hash)
+            .bind(user_id)
+            .bind(old_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     // pub async fn add_to_workspace(&self, ws_id: i64, pool: &sqlx::PgPool) -> Result<User, AppError> {
     //     let user = sqlx::query_as("update users set ws_id = $1 where id = $2 and ws_id = 0 returning *")
     //         .bind(ws_id)
@@ -142,29 +243,16 @@ impl AppState {
     // }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let hash = argon2
-        .hash_password(password.as_bytes(), &salt)?
-        .to_string();
-    Ok(hash)
-}
-
-fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
-    let argon2 = Argon2::default();
-    let parsed_hash = PasswordHash::new(password_hash)?;
-    let is_valid = argon2
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok();
-    Ok(is_valid)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::AppState;
+    use crate::{
+        invite::CreateInvite,
+        password::{PasswordConfig, PasswordPolicy},
+        AppState,
+    };
     use anyhow::Result;
+    use chat_core::{ChatType, WorkspaceRole};
     #[tokio::test]
     async fn create_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -177,6 +265,7 @@ mod tests {
             fullname: fullname.into(),
             password: password.into(),
             workspace: "hkjc".into(),
+            invite: None,
         };
         let user = state.create_user(&input).await?;
 
@@ -199,16 +288,70 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn signup_should_onboard_new_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "owner@acme.org", "owner", "hunter42");
+        let user = state.create_user(&input).await?;
+
+        let ws = state.find_workspace_by_name("acme").await?.unwrap();
+        assert_eq!(ws.owner_id, user.id);
+        assert_eq!(state.workspace_role(&user).await?, WorkspaceRole::Owner);
+        let chats = state.fetch_chats_all(ws.id as _).await?;
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].name.as_deref(), Some("general"));
+        assert_eq!(chats[0].r#type, ChatType::PublicChannel);
+        assert_eq!(chats[0].members, vec![user.id]);
+
+        // joining the workspace doesn't change the owner
+        let invite = state.create_invite(&user, &CreateInvite::default()).await?;
+        let mut input = CreateUser::new("acme", "member@acme.org", "member", "hunter42");
+        input.invite = Some(invite.code);
+        let member = state.create_user(&input).await?;
+        assert_eq!(state.workspace_role(&member).await?, WorkspaceRole::Member);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_signup_should_not_leave_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // fullname is longer than the column allows
+        let input = CreateUser::new("orphan", "orphan@acme.org", "x".repeat(65), "hunter42");
+        assert!(state.create_user(&input).await.is_err());
+        assert!(state.find_workspace_by_name("orphan").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_user_should_rehash_weaker_password() -> Result<()> {
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.auth.password.argon2.iterations = 3)
+                .await?;
+        let input = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+        let user = state.verify_user(input).await?.expect("user should exist");
+        let (password_hash,): (String,) =
+            sqlx::query_as("select password_hash from users where id = $1")
+                .bind(user.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert!(password_hash.contains("t=3"));
+        assert!(!state.password.needs_rehash(&password_hash));
+
+        let input = SigninUser::new("zack.j.chen@hkjc.org.hk", "Jiajia520");
+        assert!(state.verify_user(input).await?.is_some());
+        Ok(())
+    }
+
     #[test]
-    fn password_hash_should_work() {
+    fn password_hash_should_work() -> Result<()> {
+        let policy = PasswordPolicy::new(&PasswordConfig::default())?;
         let password = "password";
-        let hash = hash_password(password).unwrap();
+        let hash = policy.hash(password)?;
         assert_ne!(password, hash);
         println!("Hash: {}", hash);
 
-        let valiad1 = verify_password(password, &hash).is_ok();
-        let valiad2 = verify_password("password1", &hash).is_ok();
-        assert!(valiad1);
-        assert!(valiad2);
+        assert!(policy.verify(password, &hash)?);
+        assert!(!policy.verify("password1", &hash)?);
+        Ok(())
     }
 }
@@ -1,19 +1,94 @@
 use crate::models::ChatUser;
-use crate::{error::AppError, AppState};
-use chat_core::WorkSpace;
+use crate::{chat::ChatOp, error::AppError, AppState};
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    Chat, Scope, User, UserClaims, WorkSpace, WorkspaceAction, WorkspaceRole,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgExecutor, Postgres, Transaction};
+use std::io;
+use tokio::fs;
+use utoipa::ToSchema;
+
+/// created by the initial migration, it can't be deleted.
+/// Users left without any workspace fall back to it, they can't sign in until invited again.
+pub const DEFAULT_WORKSPACE_ID: i64 = 1;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UpdateWorkspace {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TransferOwnership {
+    /// the new owner, a member of the workspace
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct WorkspaceDetails {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub member_count: i64,
+    pub chat_count: i64,
+    #[schema(value_type=String)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserRole {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct JoinWorkspace {
+    /// name of the workspace
+    pub workspace: String,
+    /// invite code of the workspace
+    pub invite: String,
+}
+
+/// a workspace the user is a member of
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq, Eq, ToSchema)]
+pub struct UserWorkspace {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub role: WorkspaceRole,
+    /// the active workspace of the token used for this request
+    pub current: bool,
+    #[schema(value_type=String)]
+    pub joined_at: DateTime<Utc>,
+}
 
 impl AppState {
-    pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<WorkSpace, AppError> {
+    /// executor: the pool, or a transaction to create the workspace along with its owner
+    pub async fn create_workspace(
+        &self,
+        name: &str,
+        user_id: u64,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<WorkSpace, AppError> {
         let ws =
             sqlx::query_as("insert into workspaces (name, owner_id) values ($1, $2) returning *")
                 .bind(name)
                 .bind(user_id as i64)
-                .fetch_one(&self.pool)
+                .fetch_one(executor)
                 .await?;
 
         Ok::<WorkSpace, AppError>(ws)
     }
 
+    pub async fn find_workspace_by_id(&self, id: i64) -> Result<Option<WorkSpace>, AppError> {
+        let ws = sqlx::query_as("select * from workspaces where id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(ws)
+    }
+
     pub async fn find_workspace_by_name(
         &self,
         name: impl Into<String>,
@@ -37,31 +112,433 @@ impl AppState {
         Ok(ws)
     }
 
-    #[allow(dead_code)]
+    /// the owner must be a member of the workspace, the previous owner becomes an admin
     pub async fn update_workspace_owner(
         &self,
         wp: &WorkSpace,
         owner_id: u64,
+        executor: impl PgExecutor<'_>,
     ) -> Result<WorkSpace, AppError> {
         let ws = sqlx::query_as(
             r#"
-                update workspaces set owner_id = $1
-                where id = $2
-                and id = (select ws_id from users where id = $1)
-                returning *
+                with ws as (
+                    update workspaces set owner_id = $1
+                    where id = $2
+                    and exists (select 1 from workspace_members where ws_id = $2 and user_id = $1)
+                    returning *
+                ), roles as (
+                    update workspace_members set role = case when user_id = $1 then 'owner' else 'admin' end
+                    where ws_id = $2 and (user_id = $1 or role = 'owner')
+                    and exists (select 1 from ws)
+                )
+                select * from ws
             "#,
         )
         .bind(owner_id as i64)
         .bind(wp.id)
-        .fetch_one(&self.pool)
+        .fetch_one(executor)
         .await?;
         Ok(ws)
     }
 
+    /// executor: the pool, or a transaction to add the member along with the user
+    pub async fn add_workspace_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+        role: WorkspaceRole,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            insert into workspace_members (ws_id, user_id, role) values ($1, $2, $3)
+            on conflict (ws_id, user_id) do nothing
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .bind(role)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// join another workspace with an invite, e.g. a consultant working for several clients
+    pub async fn join_workspace(
+        &self,
+        user: &User,
+        input: &JoinWorkspace,
+    ) -> Result<UserWorkspace, AppError> {
+        let ws = self
+            .find_workspace_by_name(&input.workspace)
+            .await?
+            .ok_or_else(|| {
+                AppError::InvalidInvite("invite is invalid, expired or used up".to_string())
+            })?;
+        if self.find_workspace_of_user(user.id, ws.id).await?.is_some() {
+            return Err(AppError::InvalidInput(format!(
+                "already a member of workspace {}",
+                ws.name
+            )));
+        }
+        let mut tx = self.pool.begin().await?;
+        self.consume_invite(&input.invite, ws.id, &user.email, &mut *tx)
+            .await?;
+        self.add_workspace_member(ws.id, user.id, WorkspaceRole::Member, &mut *tx)
+            .await?;
+        tx.commit().await?;
+        // the member may be removed right after joining
+        self.find_workspace_of_user(user.id, ws.id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace {} not found", ws.name)))
+    }
+
+    /// workspaces the user is a member of, the active one of `current` is flagged
+    pub async fn list_user_workspaces(
+        &self,
+        user_id: i64,
+        current: i64,
+    ) -> Result<Vec<UserWorkspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            select w.id, w.name, w.owner_id, m.role, w.id = $2 as current, m.joined_at
+            from workspace_members m join workspaces w on m.ws_id = w.id
+            where m.user_id = $1
+            order by m.joined_at asc, w.id asc
+            "#,
+        )
+        .bind(user_id)
+        .bind(current)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(workspaces)
+    }
+
+    async fn find_workspace_of_user(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Option<UserWorkspace>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            select w.id, w.name, w.owner_id, m.role, true as current, m.joined_at
+            from workspace_members m join workspaces w on m.ws_id = w.id
+            where m.user_id = $1 and m.ws_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(ws)
+    }
+
+    /// make the workspace active in the session, and the default one of the next signin.
+    /// Returns the user with `ws_id` set to it, sign a new access token for it.
+    pub async fn switch_workspace(
+        &self,
+        user: &User,
+        ws_id: i64,
+        session_id: Option<i64>,
+    ) -> Result<(User, UserWorkspace), AppError> {
+        let ws = self
+            .find_workspace_of_user(user.id, ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace {} not found", ws_id)))?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("update users set ws_id = $1 where id = $2")
+            .bind(ws_id)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("update sessions set ws_id = $1 where id = $2 and user_id = $3")
+            .bind(ws_id)
+            .bind(session_id)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        let user = User {
+            ws_id,
+            ..user.clone()
+        };
+        Ok((user, ws))
+    }
+
+    /// role of the user in its active workspace, it's not found if the user isn't a member of it
+    pub async fn workspace_role(&self, user: &User) -> Result<WorkspaceRole, AppError> {
+        let rec: Option<(WorkspaceRole,)> =
+            sqlx::query_as("select role from workspace_members where ws_id = $1 and user_id = $2")
+                .bind(user.ws_id)
+                .bind(user.id)
+                .fetch_optional(&self.pool)
+                .await?;
+        let (role,) = rec.ok_or(AppError::NotWorkspaceMember(user.ws_id))?;
+        Ok(role)
+    }
+
+    /// change the role of a user in the workspace of `actor`.
+    /// Both the current and the new role must be lower than the role of `actor`,
+    /// the owner is changed by transferring the ownership.
+    pub async fn update_user_role(
+        &self,
+        actor: &User,
+        user_id: i64,
+        role: WorkspaceRole,
+    ) -> Result<ChatUser, AppError> {
+        let actor_role = self.workspace_role(actor).await?;
+        let target = sqlx::query_as::<_, ChatUser>(
+            r#"
+            select u.id, u.fullname, u.email, u.title, u.timezone, u.avatar, m.role
+            from workspace_members m join users u on m.user_id = u.id
+            where m.user_id = $1 and m.ws_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(actor.ws_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("user {} not found", user_id)))?;
+        if !actor_role.can(WorkspaceAction::ManageRoles)
+            || target.role >= actor_role
+            || role >= actor_role
+        {
+            return Err(
+                PermissionDenied::new(Permission::Action(WorkspaceAction::ManageRoles)).into(),
+            );
+        }
+        sqlx::query("update workspace_members set role = $1 where ws_id = $2 and user_id = $3")
+            .bind(role)
+            .bind(actor.ws_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(ChatUser { role, ..target })
+    }
+
+    /// claims of the access token signed for the user, with all the scopes of a session
+    pub async fn user_claims(
+        &self,
+        user: User,
+        session_id: Option<i64>,
+    ) -> Result<UserClaims, AppError> {
+        let role = self.workspace_role(&user).await?;
+        Ok(UserClaims {
+            user,
+            role,
+            scopes: Scope::all(),
+            session_id,
+        })
+    }
+
+    pub async fn fetch_workspace_details(&self, ws_id: i64) -> Result<WorkspaceDetails, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            select w.id, w.name, w.owner_id,
+                (select count(*) from workspace_members m where m.ws_id = w.id) as member_count,
+                (select count(*) from chats c where c.ws_id = w.id) as chat_count,
+                w.created_at
+            from workspaces w where w.id = $1
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace {} not found", ws_id)))?;
+        Ok(ws)
+    }
+
+    pub async fn rename_workspace(
+        &self,
+        ws_id: i64,
+        name: &str,
+    ) -> Result<WorkspaceDetails, AppError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > 32 {
+            return Err(AppError::InvalidInput(
+                "workspace name must be 1 to 32 characters".to_string(),
+            ));
+        }
+        let ret = sqlx::query("update workspaces set name = $1 where id = $2")
+            .bind(name)
+            .bind(ws_id)
+            .execute(&self.pool)
+            .await;
+        match ret {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                return Err(AppError::WorkspaceAlreadyExists(name.to_string()))
+            }
+            ret => ret?,
+        };
+        self.fetch_workspace_details(ws_id).await
+    }
+
+    /// transfer the ownership of the workspace of `owner` to another member
+    pub async fn transfer_workspace_ownership(
+        &self,
+        owner: &User,
+        user_id: i64,
+    ) -> Result<WorkspaceDetails, AppError> {
+        let ws = self
+            .find_workspace_by_id(owner.ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace {} not found", owner.ws_id)))?;
+        if ws.owner_id != owner.id {
+            return Err(PermissionDenied::new(Permission::Role(WorkspaceRole::Owner)).into());
+        }
+        if self.find_workspace_of_user(user_id, ws.id).await?.is_none() {
+            return Err(AppError::NotFound(format!("user {} not found", user_id)));
+        }
+        self.update_workspace_owner(&ws, user_id as u64, &self.pool)
+            .await?;
+        self.fetch_workspace_details(ws.id).await
+    }
+
+    /// remove a user with a lower role from the workspace of `actor`, and from its chats
+    pub async fn remove_workspace_member(
+        &self,
+        actor: &User,
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        let actor_role = self.workspace_role(actor).await?;
+        let target = User {
+            id: user_id,
+            ws_id: actor.ws_id,
+            ..actor.clone()
+        };
+        let role = match self.workspace_role(&target).await {
+            Err(AppError::NotWorkspaceMember(_)) => {
+                return Err(AppError::NotFound(format!("user {} not found", user_id)))
+            }
+            ret => ret?,
+        };
+        if !actor_role.can(WorkspaceAction::RemoveUser) || role >= actor_role {
+            return Err(
+                PermissionDenied::new(Permission::Action(WorkspaceAction::RemoveUser)).into(),
+            );
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("delete from workspace_members where ws_id = $1 and user_id = $2")
+            .bind(actor.ws_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        self.leave_chats(user_id, Some(actor.ws_id), &mut tx)
+            .await?;
+        self.leave_workspace(actor.ws_id, Some(user_id), &mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// delete the workspace with its chats, messages, invites and files
+    pub async fn delete_workspace(&self, ws_id: i64) -> Result<(), AppError> {
+        if ws_id == DEFAULT_WORKSPACE_ID {
+            return Err(AppError::InvalidInput(
+                "the default workspace can't be deleted".to_string(),
+            ));
+        }
+        let mut tx = self.pool.begin().await?;
+        let chats: Vec<Chat> = sqlx::query_as(
+            r#"
+            select id, ws_id, name, type, chat_members_of(id) as members, archived_at, created_at
+            from chats where ws_id = $1
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        for chat in &chats {
+            self.notify_chat_updated(ChatOp::Delete, chat, &chat.members, &mut tx)
+                .await?;
+        }
+        sqlx::query(
+            "delete from messages where chat_id in (select id from chats where ws_id = $1)",
+        )
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("delete from chats where ws_id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("delete from workspace_invites where ws_id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("delete from workspace_members where ws_id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "update personal_access_tokens set ws_id = null, revoked_at = coalesce(revoked_at, now()) where ws_id = $1",
+        )
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+        self.leave_workspace(ws_id, None, &mut tx).await?;
+        let ret = sqlx::query("delete from workspaces where id = $1")
+            .bind(ws_id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("workspace {} not found", ws_id)));
+        }
+        tx.commit().await?;
+
+        let dir = self.config.server.base_dir.join(ws_id.to_string());
+        match fs::remove_dir_all(&dir).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// the user (all users if None) leaves the workspace: its default workspace falls back to
+    /// another one it's a member of, and its sessions in the workspace to the default one
+    async fn leave_workspace(
+        &self,
+        ws_id: i64,
+        user_id: Option<i64>,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            update users u set ws_id = coalesce(
+                (select m.ws_id from workspace_members m
+                where m.user_id = u.id and m.ws_id <> $1
+                order by m.joined_at asc limit 1),
+                $3)
+            where u.ws_id = $1 and ($2::bigint is null or u.id = $2)
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .bind(DEFAULT_WORKSPACE_ID)
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query(
+            r#"
+            update sessions set ws_id = null
+            where ws_id = $1 and ($2::bigint is null or user_id = $2)
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
     /// id: ws_id
     pub async fn fetch_workspace_all_users(&self, id: u64) -> Result<Vec<ChatUser>, AppError> {
         let ws = sqlx::query_as(
-            "select id, fullname, email from users where ws_id = $1 order by id asc",
+            r#"
+            select u.id, u.fullname, u.email, u.title, u.timezone, u.avatar, m.role
+            from workspace_members m join users u on m.user_id = u.id
+            where m.ws_id = $1 order by u.id asc
+            "#,
         )
         .bind(id as i64)
         .fetch_all(&self.pool)
@@ -72,13 +549,34 @@ impl AppState {
 
 #[cfg(test)]
 mod tests {
-    use crate::{user::CreateUser, AppState};
+    use super::*;
+    use crate::{invite::CreateInvite, user::CreateUser};
     use anyhow::Result;
+
+    #[tokio::test]
+    async fn user_claims_should_have_workspace_role() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let member = state.find_user_by_id(2).await?.unwrap();
+        assert_eq!(state.workspace_role(&owner).await?, WorkspaceRole::Owner);
+        assert_eq!(state.workspace_role(&member).await?, WorkspaceRole::Member);
+
+        let token = state.ek.sign(state.user_claims(owner, None).await?)?;
+        let claims = state.dk.verify_claims(&token)?.custom;
+        assert_eq!(claims.role, WorkspaceRole::Owner);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_create_by_user_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         // 创建一个user,它将会插入ws_id为1, user_id为1
-        let input = CreateUser::new("test1", "email", "fullname", "password");
+        let inviter = state.find_user_by_id(2).await?.unwrap();
+        let invite = state
+            .create_invite(&inviter, &CreateInvite::default())
+            .await?;
+        let mut input = CreateUser::new("test1", "email", "fullname", "hunter42");
+        input.invite = Some(invite.code);
         let user = state.create_user(&input).await.unwrap();
         assert_eq!(user.ws_id, 2);
         assert_eq!(user.id, 7);
@@ -91,21 +589,188 @@ mod tests {
         let (_tdb, state) = AppState::new_for_test().await?;
         // 创建一个workspace, 默认owner_id为0
         let ws = state
-            .create_workspace("test-create-workspace", 2)
+            .create_workspace("test-create-workspace", 2, &state.pool)
             .await
             .unwrap();
         assert_eq!(ws.name, "test-create-workspace");
         assert_eq!(ws.owner_id, 2);
 
-        let input = CreateUser::new("test-create-workspace", "email", "fullname", "password");
+        // the workspace has no user to invite yet
+        let mut inviter = state.find_user_by_id(2).await?.unwrap();
+        inviter.ws_id = ws.id;
+        let invite = state
+            .create_invite(&inviter, &CreateInvite::default())
+            .await?;
+        let mut input = CreateUser::new("test-create-workspace", "email", "fullname", "hunter42");
+        input.invite = Some(invite.code);
         let user = state.create_user(&input).await.unwrap();
 
         // 更新workspace的owner_id
         let ws = state
-            .update_workspace_owner(&ws, user.id as u64)
+            .update_workspace_owner(&ws, user.id as u64, &state.pool)
             .await
             .unwrap();
         assert_eq!(ws.owner_id, user.id);
+        assert_eq!(state.workspace_role(&user).await?, WorkspaceRole::Owner);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_user_role_should_follow_matrix() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("update workspace_members set role = 'admin' where user_id = 2")
+            .execute(&state.pool)
+            .await?;
+        let admin = state.find_user_by_id(2).await?.unwrap();
+        let member = state.find_user_by_id(4).await?.unwrap();
+
+        let user = state
+            .update_user_role(&admin, 3, WorkspaceRole::Guest)
+            .await?;
+        assert_eq!(user.role, WorkspaceRole::Guest);
+        // admins can't make admins
+        let ret = state
+            .update_user_role(&admin, 3, WorkspaceRole::Admin)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_user_role(&member, 3, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        // user 1 is in another workspace
+        let ret = state
+            .update_user_role(&admin, 1, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_and_switch_workspace_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.unwrap();
+        let user = state.find_user_by_id(6).await?.unwrap();
+        let created = state
+            .create_invite(&owner, &CreateInvite::default())
+            .await?;
+        let input = JoinWorkspace {
+            workspace: "default".to_string(),
+            invite: created.code,
+        };
+        let ws = state.join_workspace(&user, &input).await?;
+        assert_eq!((ws.id, ws.role), (1, WorkspaceRole::Member));
+        let ret = state.join_workspace(&user, &input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let workspaces = state.list_user_workspaces(user.id, user.ws_id).await?;
+        let ids: Vec<_> = workspaces.iter().map(|ws| (ws.id, ws.current)).collect();
+        assert_eq!(ids, vec![(2, true), (1, false)]);
+
+        let (switched, _) = state.switch_workspace(&user, 1, None).await?;
+        assert_eq!(switched.ws_id, 1);
+        assert_eq!(state.find_user_by_id(6).await?.unwrap().ws_id, 1);
+        let users = state.fetch_workspace_all_users(1).await?;
+        assert_eq!(users.len(), 2);
+        // not a member of workspace 3
+        let ret = state.switch_workspace(&user, 3, None).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let other = User { ws_id: 3, ..user };
+        assert!(matches!(
+            state.workspace_role(&other).await,
+            Err(AppError::NotWorkspaceMember(3))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_member_and_transfer_ownership_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "owner@acme.org", "owner", "hunter42");
+        let owner = state.create_user(&input).await?;
+        let user = state.find_user_by_id(6).await?.unwrap();
+        state
+            .add_workspace_member(owner.ws_id, user.id, WorkspaceRole::Admin, &state.pool)
+            .await?;
+        let member = state.find_user_by_id(5).await?.unwrap();
+        state
+            .add_workspace_member(owner.ws_id, member.id, WorkspaceRole::Member, &state.pool)
+            .await?;
+        let (admin, _) = state.switch_workspace(&user, owner.ws_id, None).await?;
+
+        // admins can't remove admins
+        let ret = state.remove_workspace_member(&admin, owner.id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.transfer_workspace_ownership(&admin, member.id).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let ws = state.transfer_workspace_ownership(&owner, admin.id).await?;
+        assert_eq!((ws.owner_id, ws.member_count), (admin.id, 3));
+        assert_eq!(state.workspace_role(&owner).await?, WorkspaceRole::Admin);
+        state.remove_workspace_member(&admin, owner.id).await?;
+        let ret = state.remove_workspace_member(&admin, owner.id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        // the owner had no other workspace
+        let owner = state.find_user_by_id(owner.id).await?.unwrap();
+        assert_eq!(owner.ws_id, DEFAULT_WORKSPACE_ID);
+        // it's not a member of the default workspace, it can't sign in until invited again
+        let ret = state.issue_tokens(owner, &Default::default()).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceMember(_))));
+
+        state.remove_workspace_member(&admin, member.id).await?;
+        // the default workspace of the member is kept
+        let member = state.find_user_by_id(member.id).await?.unwrap();
+        assert_eq!(member.ws_id, 2);
+        // the owner was the only member of the general channel
+        let general = state.fetch_chats_all(admin.ws_id as _).await?;
+        assert!(general[0].members.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_workspace_should_cascade() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(uuid::Uuid::now_v7().to_string());
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.server.base_dir = base_dir.clone()).await?;
+        let input = CreateUser::new("acme", "owner@acme.org", "owner", "hunter42");
+        let owner = state.create_user(&input).await?;
+        let general = state.fetch_chats_all(owner.ws_id as _).await?;
+        sqlx::query("insert into messages (chat_id, sender_id, content) values ($1, $2, 'hi')")
+            .bind(general[0].id)
+            .bind(owner.id)
+            .execute(&state.pool)
+            .await?;
+        let dir = base_dir.join(owner.ws_id.to_string()).join("abc");
+        fs::create_dir_all(&dir).await?;
+        fs::write(dir.join("file.txt"), "hello").await?;
+        state
+            .add_workspace_member(owner.ws_id, 6, WorkspaceRole::Member, &state.pool)
+            .await?;
+
+        state.delete_workspace(owner.ws_id).await?;
+        assert!(state.find_workspace_by_id(owner.ws_id).await?.is_none());
+        assert!(state.fetch_chats_all(owner.ws_id as _).await?.is_empty());
+        assert!(!base_dir.join(owner.ws_id.to_string()).exists());
+        let user = state.find_user_by_id(6).await?.unwrap();
+        assert_eq!(state.list_user_workspaces(6, user.ws_id).await?.len(), 1);
+        let owner = state.find_user_by_id(owner.id).await?.unwrap();
+        assert_eq!(owner.ws_id, DEFAULT_WORKSPACE_ID);
+        let ret = state.workspace_role(&owner).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceMember(_))));
+
+        let ret = state.delete_workspace(DEFAULT_WORKSPACE_ID).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_workspace_should_be_unique() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.rename_workspace(2, " acme ").await?;
+        assert_eq!((ws.name.as_str(), ws.member_count), ("acme", 5));
+        let ret = state.rename_workspace(3, "acme").await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+        let ret = state.rename_workspace(3, "").await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
         Ok(())
     }
 
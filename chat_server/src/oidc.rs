@@ -0,0 +1,390 @@
+use crate::error::AppError;
+use jwt_simple::{prelude::*, reexports::ct_codecs::Base64UrlSafeNoPadding};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+/// OpenID Connect identity provider used to sign in without a chat password
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcConfig {
+    /// `<issuer>/.well-known/openid-configuration` is used for discovery
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// the callback url registered at the IdP, e.g. http://localhost:6688/api/oidc/callback
+    pub redirect_uri: String,
+    /// users signed in for the first time are created in this workspace
+    pub workspace: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".into(), "email".into(), "profile".into()]
+}
+
+/// the subset of the discovery document we need
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// the identity of the user at the IdP, from a validated id_token
+#[derive(Debug, Clone)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IdentityClaims {
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdpJwks {
+    keys: Vec<IdpJwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdpJwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    /// fetch the discovery document once, and check it's issued by the configured issuer
+    async fn metadata(&self) -> Result<&ProviderMetadata, AppError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| AppError::OidcError(format!("discovery failed: {}", e)))?
+                    .json()
+                    .await
+                    .map_err(|e| AppError::OidcError(format!("invalid discovery: {}", e)))?;
+                if metadata.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/')
+                {
+                    return Err(AppError::OidcError(format!(
+                        "issuer mismatch: {}",
+                        metadata.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// url of the IdP login page to redirect the user to
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<Url, AppError> {
+        let metadata = self.metadata().await?;
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", &self.config.scopes.join(" ")),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &code_challenge(code_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| AppError::OidcError(format!("invalid authorization endpoint: {}", e)))?;
+        Ok(url)
+    }
+
+    /// exchange the authorization code for an id_token, and validate it
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<OidcIdentity, AppError> {
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let res: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| AppError::OidcError(format!("token exchange failed: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| AppError::OidcError(format!("invalid token response: {}", e)))?;
+
+        self.verify_id_token(&res.id_token, nonce).await
+    }
+
+    /// check the signature by the IdP's JWKS, the issuer, audience, expiration and nonce
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<OidcIdentity, AppError> {
+        let metadata = self.metadata().await?;
+        let header = Token::decode_metadata(id_token)
+            .map_err(|e| AppError::OidcError(format!("invalid id_token: {}", e)))?;
+        if header.algorithm() != "RS256" {
+            return Err(AppError::OidcError(format!(
+                "unsupported id_token algorithm: {}",
+                header.algorithm()
+            )));
+        }
+
+        let jwks: IdpJwks = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| AppError::OidcError(format!("fetch jwks failed: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| AppError::OidcError(format!("invalid jwks: {}", e)))?;
+        let jwk = jwks
+            .keys
+            .iter()
+            .find(|k| {
+                k.kty == "RSA" && (header.key_id().is_none() || k.kid.as_deref() == header.key_id())
+            })
+            .ok_or_else(|| AppError::OidcError("no key found for id_token".to_string()))?;
+        let (Some(n), Some(e)) = (&jwk.n, &jwk.e) else {
+            return Err(AppError::OidcError("invalid RSA key in jwks".to_string()));
+        };
+        let key = RS256PublicKey::from_components(&decode_b64(n)?, &decode_b64(e)?)
+            .map_err(|e| AppError::OidcError(format!("invalid RSA key in jwks: {}", e)))?;
+
+        let options = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[&metadata.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.client_id])),
+            required_nonce: Some(nonce.to_string()),
+            ..Default::default()
+        };
+        let claims = key
+            .verify_token::<IdentityClaims>(id_token, Some(options))
+            .map_err(|e| AppError::OidcError(format!("invalid id_token: {}", e)))?;
+        let subject = claims
+            .subject
+            .ok_or_else(|| AppError::OidcError("id_token has no subject".to_string()))?;
+
+        Ok(OidcIdentity {
+            issuer: metadata.issuer.clone(),
+            subject,
+            email: claims.custom.email,
+            email_verified: claims.custom.email_verified,
+            name: claims.custom.name,
+        })
+    }
+}
+
+/// PKCE S256 code challenge
+fn code_challenge(code_verifier: &str) -> String {
+    let hash = Sha256::digest(code_verifier.as_bytes());
+    Base64UrlSafeNoPadding::encode_to_string(hash).expect("base64 encode should work")
+}
+
+fn decode_b64(s: &str) -> Result<Vec<u8>, AppError> {
+    Base64UrlSafeNoPadding::decode_to_vec(s, None)
+        .map_err(|e| AppError::OidcError(format!("invalid base64: {}", e)))
+}
+
+#[cfg(feature = "test-util")]
+pub mod mock {
+    use super::*;
+    use axum::{
+        extract::State,
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use jwt_simple::reexports::serde_json;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tokio::net::TcpListener;
+
+    /// a local identity provider for tests, it signs in whoever is `authorize`d
+    #[derive(Clone)]
+    pub struct MockIdp {
+        pub issuer: String,
+        inner: Arc<MockIdpInner>,
+    }
+
+    struct MockIdpInner {
+        key: RS256KeyPair,
+        issuer: String,
+        client_id: String,
+        /// code -> (code_challenge, nonce, claims)
+        codes: Mutex<HashMap<String, (String, String, MockIdentity)>>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct MockIdentity {
+        pub subject: String,
+        pub email: String,
+        pub email_verified: bool,
+        pub name: String,
+    }
+
+    impl MockIdp {
+        pub async fn start(client_id: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("bind mock idp failed");
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let key = RS256KeyPair::from_pem(include_str!("../fixtures/oidc_idp_key.pem"))
+                .expect("load mock idp key failed")
+                .with_key_id("mock");
+            let idp = Self {
+                issuer: issuer.clone(),
+                inner: Arc::new(MockIdpInner {
+                    key,
+                    issuer,
+                    client_id: client_id.to_string(),
+                    codes: Mutex::new(HashMap::new()),
+                }),
+            };
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(idp.inner.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            idp
+        }
+
+        /// what the IdP login page does: the user signs in, and is redirected back with a code
+        pub fn authorize(
+            &self,
+            authorization_url: &str,
+            identity: MockIdentity,
+        ) -> (String, String) {
+            let url = Url::parse(authorization_url).expect("invalid authorization url");
+            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            let code = uuid::Uuid::now_v7().to_string();
+            self.inner.codes.lock().unwrap().insert(
+                code.clone(),
+                (
+                    params["code_challenge"].clone(),
+                    params["nonce"].clone(),
+                    identity,
+                ),
+            );
+            (code, params["state"].clone())
+        }
+    }
+
+    async fn discovery(State(idp): State<Arc<MockIdpInner>>) -> Json<ProviderMetadata> {
+        Json(ProviderMetadata {
+            issuer: idp.issuer.clone(),
+            authorization_endpoint: format!("{}/authorize", idp.issuer),
+            token_endpoint: format!("{}/token", idp.issuer),
+            jwks_uri: format!("{}/jwks", idp.issuer),
+        })
+    }
+
+    async fn jwks(State(idp): State<Arc<MockIdpInner>>) -> Json<serde_json::Value> {
+        let components = idp.key.public_key().to_components();
+        Json(serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "alg": "RS256",
+                "kid": "mock",
+                "n": Base64UrlSafeNoPadding::encode_to_string(components.n).unwrap(),
+                "e": Base64UrlSafeNoPadding::encode_to_string(components.e).unwrap(),
+            }]
+        }))
+    }
+
+    async fn token(
+        State(idp): State<Arc<MockIdpInner>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<serde_json::Value>, axum::http::StatusCode> {
+        let bad_request = axum::http::StatusCode::BAD_REQUEST;
+        let (challenge, nonce, identity) = idp
+            .codes
+            .lock()
+            .unwrap()
+            .remove(&form["code"])
+            .ok_or(bad_request)?;
+        if code_challenge(&form["code_verifier"]) != challenge || form["client_id"] != idp.client_id
+        {
+            return Err(bad_request);
+        }
+        let claims = Claims::with_custom_claims(
+            IdentityClaims {
+                email: Some(identity.email),
+                email_verified: identity.email_verified,
+                name: Some(identity.name),
+            },
+            Duration::from_mins(5),
+        )
+        .with_issuer(&idp.issuer)
+        .with_audience(&idp.client_id)
+        .with_subject(identity.subject)
+        .with_nonce(nonce);
+        let id_token = idp.key.sign(claims).map_err(|_| bad_request)?;
+        Ok(Json(serde_json::json!({
+            "access_token": "mock",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        })))
+    }
+}
@@ -1,12 +1,31 @@
 use crate::handlers::*;
 use crate::{
-    chat::CreateChat,
+    account::DeleteAccount,
+    chat::{CreateChat, ListChats, PublicChannel, UpdateChat},
+    email_verification::VerifyEmail,
+    identity::OidcCallback,
+    invite::{CreateInvite, CreatedInvite, Invite},
     messages::{CreateMessage, ListMessages},
+    password_reset::{RequestPasswordReset, ResetPassword},
+    personal_access_token::{
+        CreatePersonalAccessToken, CreatedPersonalAccessToken, PersonalAccessToken,
+    },
+    profile::{ChangePassword, Profile, UpdateProfile},
+    session::Session,
+    totp::{MfaChallenge, RecoveryCodes, SigninTotp, TotpCode, TotpEnrollment},
     user::{CreateUser, SigninUser},
-    AuthOutput, ChatUser,
+    workspace::{
+        JoinWorkspace, TransferOwnership, UpdateUserRole, UpdateWorkspace, UserWorkspace,
+        WorkspaceDetails,
+    },
+    AuthOutput, ChatUser, RefreshToken,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, Message, User, WorkSpace};
+use chat_core::{
+    middleware::permission::{Permission, PermissionDenied},
+    utils::jwt::{Jwk, Jwks},
+    Chat, ChatType, Message, Scope, User, WorkSpace, WorkspaceAction, WorkspaceRole,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -25,14 +44,61 @@ pub trait OpenApiRouter {
     paths(
         signup_handler,
         signin_handler,
+        signin_totp_handler,
+        oidc_login_handler,
+        oidc_callback_handler,
+        refresh_handler,
+        signout_handler,
+        jwks_handler,
+        request_password_reset_handler,
+        reset_password_handler,
+        verify_email_handler,
+        resend_email_verification_handler,
+        enroll_totp_handler,
+        confirm_totp_handler,
+        disable_totp_handler,
         list_chat_handler,
         get_chat_handler,
         create_chat_handler,
+        update_chat_handler,
+        delete_chat_handler,
+        archive_chat_handler,
+        unarchive_chat_handler,
+        list_public_channels_handler,
+        join_chat_handler,
+        leave_chat_handler,
         send_message_handler,
         list_message_handler,
+        delete_message_handler,
+        typing_handler,
         list_workspace_users_handler,
+        unlock_user_handler,
+        update_user_role_handler,
+        list_workspaces_handler,
+        join_workspace_handler,
+        switch_workspace_handler,
+        get_workspace_handler,
+        update_workspace_handler,
+        delete_workspace_handler,
+        transfer_workspace_handler,
+        remove_workspace_user_handler,
+        deactivate_user_handler,
+        reactivate_user_handler,
+        create_invite_handler,
+        list_invites_handler,
+        revoke_invite_handler,
+        create_personal_access_token_handler,
+        list_personal_access_tokens_handler,
+        revoke_personal_access_token_handler,
+        get_profile_handler,
+        update_profile_handler,
+        change_password_handler,
+        delete_account_handler,
+        export_personal_data_handler,
+        list_sessions_handler,
+        revoke_session_handler,
     ),
-    components(schemas( CreateUser,User, CreateChat,Chat, ChatType, AuthOutput, ChatUser, CreateMessage,SigninUser, Message, WorkSpace,ListMessages)),
+    components(schemas( CreateUser,User, RefreshToken, Jwks, Jwk, RequestPasswordReset, ResetPassword, VerifyEmail, TotpEnrollment, TotpCode, RecoveryCodes, MfaChallenge, SigninTotp, OidcCallback, Scope, WorkspaceRole, WorkspaceAction, UpdateUserRole, UserWorkspace, JoinWorkspace, SwitchWorkspaceOutput, WorkspaceDetails, UpdateWorkspace, TransferOwnership, Permission, PermissionDenied, CreatePersonalAccessToken, CreatedPersonalAccessToken, PersonalAccessToken, Session, Profile, UpdateProfile, ChangePassword, DeleteAccount, CreateInvite, CreatedInvite, Invite, CreateChat, UpdateChat, ListChats, PublicChannel, Chat, ChatType, AuthOutput, ChatUser, CreateMessage,SigninUser, Message, WorkSpace,ListMessages)),
     modifiers(&SecurityAddon),
     tags(
         (name="chat", description="Chat related operations")
@@ -0,0 +1,173 @@
+use crate::error::AppError;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::PathBuf};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    /// a file of common or breached passwords which are rejected, one per line, case insensitive
+    pub common_passwords_file: Option<PathBuf>,
+    pub argon2: Argon2Config,
+}
+
+/// argon2id parameters of new hashes, stored hashes with weaker ones are rehashed on signin
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+/// validate, hash and verify passwords by the configured policy
+pub struct PasswordPolicy {
+    min_length: usize,
+    common_passwords: HashSet<String>,
+    params: Params,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            common_passwords_file: None,
+            argon2: Argon2Config::default(),
+        }
+    }
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new(config: &PasswordConfig) -> Result<Self, AppError> {
+        let common_passwords = match &config.common_passwords_file {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+        let params = Params::new(
+            config.argon2.memory_kib,
+            config.argon2.iterations,
+            config.argon2.parallelism,
+            None,
+        )
+        .map_err(argon2::password_hash::Error::from)?;
+        Ok(Self {
+            min_length: config.min_length,
+            common_passwords,
+            params,
+        })
+    }
+
+    pub fn validate(&self, password: &str) -> Result<(), AppError> {
+        if password.chars().count() < self.min_length {
+            return Err(AppError::WeakPassword(format!(
+                "password must be at least {} characters",
+                self.min_length
+            )));
+        }
+        if self.common_passwords.contains(&password.to_lowercase()) {
+            return Err(AppError::WeakPassword("password is too common".to_string()));
+        }
+        Ok(())
+    }
+
+    /// hash with the current parameters, `validate` new passwords first
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string();
+        Ok(hash)
+    }
+
+    /// the parameters are read from the stored hash, so old hashes still verify
+    pub fn verify(&self, password: &str, password_hash: &str) -> Result<bool, AppError> {
+        let parsed_hash = PasswordHash::new(password_hash)?;
+        let is_valid = self
+            .argon2()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok();
+        Ok(is_valid)
+    }
+
+    /// whether the stored hash is weaker than the current parameters
+    pub fn needs_rehash(&self, password_hash: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(password_hash) else {
+            return false;
+        };
+        if parsed_hash.algorithm != Algorithm::Argon2id.ident()
+            || parsed_hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+        match Params::try_from(&parsed_hash) {
+            Ok(params) => {
+                params.m_cost() < self.params.m_cost()
+                    || params.t_cost() < self.params.t_cost()
+                    || params.p_cost() < self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_policy_should_validate() -> anyhow::Result<()> {
+        let config = PasswordConfig {
+            common_passwords_file: Some("assets/common_passwords.txt".into()),
+            ..Default::default()
+        };
+        let policy = PasswordPolicy::new(&config)?;
+        assert!(matches!(
+            policy.validate("short"),
+            Err(AppError::WeakPassword(_))
+        ));
+        assert!(matches!(
+            policy.validate("Password1"),
+            Err(AppError::WeakPassword(_))
+        ));
+        assert!(policy.validate("hunter42").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn weaker_hash_should_need_rehash() -> anyhow::Result<()> {
+        let weak = PasswordPolicy::new(&PasswordConfig::default())?;
+        let hash = weak.hash("hunter42")?;
+        assert!(!weak.needs_rehash(&hash));
+
+        let mut config = PasswordConfig::default();
+        config.argon2.iterations += 1;
+        let strong = PasswordPolicy::new(&config)?;
+        assert!(strong.needs_rehash(&hash));
+        assert!(strong.verify("hunter42", &hash)?);
+        assert!(!strong.needs_rehash(&strong.hash("hunter42")?));
+        Ok(())
+    }
+}
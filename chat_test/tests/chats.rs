@@ -63,6 +63,42 @@ impl ChatServer {
         Ok(self.token.clone())
     }
 
+    async fn revoke_current_session(&self) -> Result<()> {
+        let url = format!("http://{}/api/sessions", self.addr);
+        let sessions: Vec<serde_json::Value> = self
+            .client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let current = sessions
+            .iter()
+            .find(|s| s["current"] == true)
+            .expect("current session should exist");
+        let resp = self
+            .client
+            .delete(format!("{}/{}", url, current["id"]))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    async fn update_profile(&self, body: serde_json::Value) -> Result<()> {
+        let resp = self
+            .client
+            .patch(format!("http://{}/api/me", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .json(&body)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
     async fn create_chat(&self) -> Result<Chat> {
         let url = format!("http://{}/api/chat", self.addr);
         let resp = self
@@ -86,7 +122,7 @@ impl ChatServer {
         let form = Form::new().part("file", files);
         let rep = self
             .client
-            .post(&format!("http://{}/api/upload", self.addr))
+            .post(format!("http://{}/api/upload", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .multipart(form)
             .send()
@@ -103,7 +139,7 @@ impl ChatServer {
         .to_string();
         let res = self
             .client
-            .post(&format!(
+            .post(format!(
                 "http://{}/api/chat/{}/messages",
                 self.addr, chat_id
             ))
@@ -121,11 +157,14 @@ impl ChatServer {
 }
 
 impl NotifyServer {
-    async fn new(db_url: &str, token: &str) -> anyhow::Result<Self> {
+    async fn start(db_url: &str, chat_addr: SocketAddr) -> anyhow::Result<SocketAddr> {
         let listener = tokio::net::TcpListener::bind(WILD_ADDRESS).await?;
         let addr = listener.local_addr()?;
         let mut config = notify_server::AppConfig::load().expect("failed to load config");
         config.server.db_url = db_url.to_string();
+        // fetch the public keys from chat server instead of using the configured one
+        config.auth.pk = None;
+        config.auth.jwks_url = Some(format!("http://{}/.well-known/jwks.json", chat_addr));
         println!("config db_url: {:?}", config.server.db_url);
         let app = notify_server::get_router(config).await?;
         tokio::spawn(async move {
@@ -133,6 +172,11 @@ impl NotifyServer {
                 .await
                 .unwrap();
         });
+        Ok(addr)
+    }
+
+    async fn new(db_url: &str, chat_addr: SocketAddr, token: &str) -> anyhow::Result<Self> {
+        let addr = Self::start(db_url, chat_addr).await?;
         let mut es = EventSource::get(format!("http://{}/events?access_token={}", addr, token));
 
         tokio::spawn(async move {
@@ -177,7 +221,7 @@ async fn chat_server_should_work() -> anyhow::Result<()> {
     let (tdb, state) = chat_server::AppState::new_for_test().await?;
     let server = ChatServer::new(state).await?;
     println!("server: {:?}", tdb.url());
-    NotifyServer::new(&tdb.url(), &server.token).await?;
+    NotifyServer::new(&tdb.url(), server.addr, &server.token).await?;
     let chat = server.create_chat().await?;
     // println!("chat: {:?}", chat);
     let _message = server.create_message(chat.id as u64).await?;
@@ -185,3 +229,122 @@ async fn chat_server_should_work() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn revoked_session_should_close_sse() -> anyhow::Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start(&tdb.url(), server.addr).await?;
+    let mut es = EventSource::get(format!(
+        "http://{}/events?access_token={}",
+        addr, server.token
+    ));
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+
+    server.revoke_current_session().await?;
+    // the stream is closed, and reconnecting with the token is rejected
+    let mut errors = vec![];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Err(e) = event {
+                errors.push(e);
+                if errors.len() == 2 {
+                    break;
+                }
+            }
+        }
+    })
+    .await?;
+    assert!(matches!(errors[0], reqwest_eventsource::Error::StreamEnded));
+    assert!(matches!(
+        errors[1],
+        reqwest_eventsource::Error::InvalidStatusCode(StatusCode::FORBIDDEN, _)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn profile_update_should_notify() -> anyhow::Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start(&tdb.url(), server.addr).await?;
+    let mut es = EventSource::get(format!(
+        "http://{}/events?access_token={}",
+        addr, server.token
+    ));
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+
+    server
+        .update_profile(json!({"title": "consultant", "timezone": "Asia/Hong_Kong"}))
+        .await?;
+    let msg = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Ok(Event::Message(msg)) = event {
+                if msg.event == "ProfileUpdated" {
+                    return Some(msg);
+                }
+            }
+        }
+        None
+    })
+    .await?
+    .expect("profile updated event should be sent");
+    let user: serde_json::Value = serde_json::from_str(&msg.data)?;
+    assert_eq!(user["id"], 6);
+    assert_eq!(user["title"], "consultant");
+    assert_eq!(user["timezone"], "Asia/Hong_Kong");
+    Ok(())
+}
+
+#[tokio::test]
+async fn presence_should_follow_sse_connections() -> anyhow::Result<()> {
+    let (tdb, state) = chat_server::AppState::new_for_test().await?;
+    let server = ChatServer::new(state).await?;
+    let addr = NotifyServer::start(&tdb.url(), server.addr).await?;
+    let mut es = EventSource::get(format!(
+        "http://{}/events?access_token={}",
+        addr, server.token
+    ));
+    assert!(matches!(es.next().await, Some(Ok(Event::Open))));
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(event) = es.next().await {
+            if let Ok(Event::Message(msg)) = event {
+                if msg.event == "PresenceChanged" {
+                    return Some(msg);
+                }
+            }
+        }
+        None
+    })
+    .await?
+    .expect("presence changed event should be sent");
+    let presence: notify_server::UserPresence = serde_json::from_str(&msg.data)?;
+    assert_eq!(presence.user_id, 6);
+    assert_eq!(presence.status, notify_server::PresenceStatus::Online);
+
+    let query = || async {
+        let resp = server
+            .client
+            .get(format!("http://{}/presence?ids=2,6", addr))
+            .header("Authorization", format!("Bearer {}", server.token))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        anyhow::Ok(resp.json::<Vec<notify_server::UserPresence>>().await?)
+    };
+    let ret = query().await?;
+    assert_eq!(ret[0].status, notify_server::PresenceStatus::Offline);
+    assert_eq!(ret[1].status, notify_server::PresenceStatus::Online);
+
+    // the user is offline once its only stream is closed, it's noticed on the next keep-alive
+    drop(es);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while query().await?[1].status != notify_server::PresenceStatus::Offline {
+            sleep(Duration::from_millis(200)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(())
+}
@@ -0,0 +1,21 @@
+-- refresh tokens are opaque random strings, only the sha256 hash is stored
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    token_hash CHAR(64) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- set when the token is used(rotated) or signed out
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS refresh_token_hash_index ON refresh_tokens(token_hash);
+CREATE INDEX IF NOT EXISTS refresh_token_user_id_index ON refresh_tokens(user_id);
+
+-- revoked access tokens(jwt id), rows can be removed once expires_at is passed
+CREATE TABLE IF NOT EXISTS revoked_tokens(
+    jti VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,11 @@
+-- single-use password reset tokens, only the sha256 hash is stored
+CREATE TABLE IF NOT EXISTS password_reset_tokens(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    token_hash CHAR(64) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS password_reset_token_hash_index ON password_reset_tokens(token_hash);
@@ -0,0 +1,15 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS verified_at TIMESTAMPTZ;
+-- existing accounts are trusted
+UPDATE users SET verified_at = created_at;
+
+-- single-use email verification tokens, only the sha256 hash is stored
+CREATE TABLE IF NOT EXISTS email_verification_tokens(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    token_hash CHAR(64) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS email_verification_token_hash_index ON email_verification_tokens(token_hash);
@@ -0,0 +1,35 @@
+-- TOTP(RFC 6238) secret of user, it's enabled after the first code is confirmed
+CREATE TABLE IF NOT EXISTS user_totp(
+    user_id BIGINT PRIMARY KEY REFERENCES users(id),
+    -- base32 encoded secret
+    secret VARCHAR(64) NOT NULL,
+    enabled_at TIMESTAMPTZ,
+    -- a code can't be used twice
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- single-use recovery codes, only the sha256 hash is stored
+CREATE TABLE IF NOT EXISTS totp_recovery_codes(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    code_hash CHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS totp_recovery_code_user_id_index ON totp_recovery_codes(user_id);
+
+-- issued when password is verified for a user with TOTP enabled,
+-- it must be completed with a code before the access token is issued
+CREATE TABLE IF NOT EXISTS mfa_challenges(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    token_hash CHAR(64) NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS mfa_challenge_token_hash_index ON mfa_challenges(token_hash);
@@ -0,0 +1,24 @@
+-- pending OIDC logins, created when the user is redirected to the IdP
+CREATE TABLE IF NOT EXISTS oidc_login_states(
+    id BIGSERIAL PRIMARY KEY,
+    -- sha256 of the state parameter
+    state_hash CHAR(64) NOT NULL,
+    nonce VARCHAR(64) NOT NULL,
+    -- PKCE code verifier
+    code_verifier VARCHAR(128) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS oidc_login_state_hash_index ON oidc_login_states(state_hash);
+
+-- users signed in by an IdP, identified by (issuer, subject)
+CREATE TABLE IF NOT EXISTS user_identities(
+    issuer VARCHAR(256) NOT NULL,
+    subject VARCHAR(256) NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identity_user_id_index ON user_identities(user_id);
@@ -0,0 +1,18 @@
+-- named personal access tokens for automation, limited to their scopes
+CREATE TABLE IF NOT EXISTS personal_access_tokens(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    name VARCHAR(64) NOT NULL,
+    -- sha256 of the token, the token itself is only shown once
+    token_hash CHAR(64) NOT NULL,
+    -- e.g. {chats:read,messages:write}
+    scopes TEXT[] NOT NULL,
+    -- never expires if null
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS personal_access_token_hash_index ON personal_access_tokens(token_hash);
+CREATE INDEX IF NOT EXISTS personal_access_token_user_id_index ON personal_access_tokens(user_id);
@@ -0,0 +1,14 @@
+-- failed signin attempts per account(email) and per client IP
+CREATE TABLE IF NOT EXISTS signin_attempts(
+    -- account | ip
+    kind VARCHAR(16) NOT NULL,
+    -- lowercase email or IP address
+    subject VARCHAR(64) NOT NULL,
+    -- failures since the last success, reset after the lockout window
+    failures INT NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- exponential backoff, signin is rejected before this time
+    next_attempt_at TIMESTAMPTZ,
+    locked_until TIMESTAMPTZ,
+    PRIMARY KEY (kind, subject)
+);
@@ -0,0 +1,16 @@
+-- a signin session(device), access tokens carry its id and are rejected once it's revoked
+CREATE TABLE IF NOT EXISTS sessions(
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    device_name VARCHAR(64),
+    user_agent VARCHAR(256),
+    ip VARCHAR(64),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS session_user_id_index ON sessions(user_id);
+
+-- refresh tokens are bound to the session they are issued for
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS session_id BIGINT REFERENCES sessions(id);
@@ -0,0 +1,18 @@
+-- invitations to join a workspace, only the sha256 hash of the code is stored
+CREATE TABLE IF NOT EXISTS workspace_invites(
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    created_by BIGINT NOT NULL REFERENCES users(id),
+    code_hash CHAR(64) NOT NULL,
+    -- only the user signing up with this email can use it
+    email VARCHAR(64),
+    -- unlimited if null
+    max_uses INT,
+    uses INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS workspace_invite_code_hash_index ON workspace_invites(code_hash);
+CREATE INDEX IF NOT EXISTS workspace_invite_ws_id_index ON workspace_invites(ws_id);
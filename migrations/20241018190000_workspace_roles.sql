@@ -0,0 +1,6 @@
+-- role of the user in its workspace: owner, admin, member or guest
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR(16) NOT NULL DEFAULT 'member';
+
+UPDATE users SET role = 'owner'
+FROM workspaces
+WHERE workspaces.owner_id = users.id AND workspaces.id = users.ws_id;
@@ -0,0 +1,25 @@
+-- a user can be a member of several workspaces, with a role in each of them.
+-- users.ws_id is the workspace signed in to by default, the last one switched to.
+CREATE TABLE IF NOT EXISTS workspace_members(
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    role VARCHAR(16) NOT NULL DEFAULT 'member',
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_member_user_id_index ON workspace_members(user_id);
+
+INSERT INTO workspace_members(ws_id, user_id, role)
+SELECT ws_id, id, role FROM users
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE users DROP COLUMN IF EXISTS role;
+
+-- the active workspace of a session, it's kept when the access token is refreshed
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ws_id BIGINT REFERENCES workspaces(id);
+UPDATE sessions SET ws_id = users.ws_id FROM users WHERE users.id = sessions.user_id;
+
+-- a personal access token works in the workspace it's created in
+ALTER TABLE personal_access_tokens ADD COLUMN IF NOT EXISTS ws_id BIGINT REFERENCES workspaces(id);
+UPDATE personal_access_tokens SET ws_id = users.ws_id FROM users WHERE users.id = personal_access_tokens.user_id;
@@ -0,0 +1,6 @@
+-- profile of the user, shared by all its workspaces
+ALTER TABLE users ADD COLUMN IF NOT EXISTS title VARCHAR(64);
+-- IANA time zone name, e.g. Asia/Hong_Kong
+ALTER TABLE users ADD COLUMN IF NOT EXISTS timezone VARCHAR(64);
+-- url of a file uploaded via /api/upload
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar VARCHAR(256);
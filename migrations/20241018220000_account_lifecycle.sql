@@ -0,0 +1,4 @@
+-- a deactivated user can't sign in and its tokens are rejected, until it's reactivated
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deactivated_at TIMESTAMPTZ;
+-- a deleted user is anonymized, the row is kept so its messages show as "deleted user"
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- archived chats are read-only and hidden from the default list until they're restored
+ALTER TABLE chats ADD COLUMN IF NOT EXISTS archived_at TIMESTAMPTZ;
@@ -0,0 +1,52 @@
+-- chat membership with per-member state, it replaces chats.members
+CREATE TABLE IF NOT EXISTS chat_members(
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    -- owner or member
+    role VARCHAR(16) NOT NULL DEFAULT 'member',
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- the last message read by the member
+    last_read_id BIGINT,
+    muted BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS chat_member_user_id_index ON chat_members(user_id);
+
+-- users that don't exist are dropped, e.g. the 0 of the default general channel
+INSERT INTO chat_members(chat_id, user_id, joined_at)
+SELECT c.id, m.user_id, c.created_at
+FROM chats c, unnest(c.members) AS m(user_id)
+WHERE EXISTS (SELECT 1 FROM users u WHERE u.id = m.user_id)
+ON CONFLICT DO NOTHING;
+
+-- the member ids of a chat, `Chat.members` of the API
+CREATE OR REPLACE FUNCTION chat_members_of(BIGINT)
+RETURNS BIGINT[]
+AS $$
+    SELECT coalesce(array_agg(user_id ORDER BY joined_at, user_id), '{}')
+    FROM chat_members WHERE chat_id = $1
+$$
+LANGUAGE sql STABLE;
+
+-- chat_server notifies chat_updated itself, the members are not in the chats row anymore
+DROP TRIGGER IF EXISTS chat_updated_trigger ON chats;
+DROP FUNCTION IF EXISTS notify_chat_updated();
+
+CREATE OR REPLACE FUNCTION notify_message_added()
+RETURNS TRIGGER
+AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        RAISE NOTICE 'message_added: %', NEW;
+        PERFORM pg_notify('message_added', json_build_object(
+            'message', NEW,
+            'members', chat_members_of(NEW.chat_id)
+        )::text);
+    END IF;
+    RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+ALTER TABLE chats DROP COLUMN IF EXISTS members;
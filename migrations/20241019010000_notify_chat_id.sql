@@ -0,0 +1,14 @@
+-- the members of large channels don't fit in a pg_notify payload(8000 bytes),
+-- notify_server loads them from chat_members by the chat id
+CREATE OR REPLACE FUNCTION notify_message_added()
+RETURNS TRIGGER
+AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        RAISE NOTICE 'message_added: %', NEW;
+        PERFORM pg_notify('message_added', json_build_object('message', NEW)::text);
+    END IF;
+    RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,2 @@
+-- signin accepts emails of any length, a long one must be recorded as a failure too
+ALTER TABLE signin_attempts ALTER COLUMN subject TYPE TEXT;
@@ -0,0 +1,7 @@
+-- chats migrated to chat_members have no owner, the workspace owner owns the general channel.
+-- Other migrated chats stay without an owner, they're renamed or managed by the admins only.
+UPDATE chat_members m SET role = 'owner'
+FROM chats c, workspaces w
+WHERE m.chat_id = c.id AND c.ws_id = w.id AND m.user_id = w.owner_id
+    AND c.name = 'general' AND c.type = 'public_channel'
+    AND NOT EXISTS (SELECT 1 FROM chat_members o WHERE o.chat_id = c.id AND o.role = 'owner');
@@ -6,6 +6,8 @@ use std::fs;
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,7 +18,24 @@ pub struct ServerConfig {
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthConfig {
-    pub pk: String,
+    /// public key to verify the token, not needed if `jwks_url` is set
+    #[serde(default)]
+    pub pk: Option<String>,
+    /// chat_server's JSON Web Key Set, e.g. http://localhost:6688/.well-known/jwks.json
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PresenceConfig {
+    /// seconds without activity before an online user is away
+    pub away_after: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self { away_after: 300 }
+    }
 }
 
 impl AppConfig {
@@ -32,7 +51,11 @@ impl AppConfig {
             _ => bail!("Config file not found"),
         };
 
-        Ok(ret?)
+        let config: AppConfig = ret?;
+        if config.auth.pk.is_none() && config.auth.jwks_url.is_none() {
+            bail!("Either auth.pk or auth.jwks_url is required");
+        }
+        Ok(config)
     }
 }
 
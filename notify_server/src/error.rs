@@ -25,6 +25,14 @@ pub enum AppError {
     JwtError(#[from] jwt_simple::Error),
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("Sqlx error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+    #[error("Token has been revoked")]
+    TokenRevoked,
+    #[error("Account is deactivated")]
+    AccountDeactivated,
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
 }
 
 impl IntoResponse for AppError {
@@ -32,6 +40,10 @@ impl IntoResponse for AppError {
         let status = match self {
             AppError::JwtError(_) => axum::http::StatusCode::FORBIDDEN,
             AppError::IOError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::SqlxError(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::TokenRevoked => axum::http::StatusCode::FORBIDDEN,
+            AppError::AccountDeactivated => axum::http::StatusCode::FORBIDDEN,
+            AppError::InvalidInput(_) => axum::http::StatusCode::BAD_REQUEST,
         };
 
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
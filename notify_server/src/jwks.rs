@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use crate::AppState;
+use chat_core::utils::jwt::{DecodingKey, Jwks};
+use tracing::{info, warn};
+
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 5);
+
+/// fetch the public keys from chat_server, and refresh them periodically so
+/// rotated keys are picked up without restarting notify_server
+pub async fn setup_jwks_refresher(state: AppState) -> anyhow::Result<()> {
+    let Some(url) = state.config.auth.jwks_url.clone() else {
+        return Ok(());
+    };
+    let client = reqwest::Client::new();
+    let dk = fetch_decoding_key(&client, &url).await?;
+    *state.dk.write().expect("dk lock poisoned") = dk;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JWKS_REFRESH_INTERVAL);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match fetch_decoding_key(&client, &url).await {
+                Ok(dk) => *state.dk.write().expect("dk lock poisoned") = dk,
+                Err(e) => warn!("Failed to refresh jwks from {}: {:?}", url, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn fetch_decoding_key(client: &reqwest::Client, url: &str) -> anyhow::Result<DecodingKey> {
+    let jwks: Jwks = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    info!("Fetched {} keys from {}", jwks.keys.len(), url);
+    DecodingKey::from_jwks(&jwks)
+}
@@ -1,24 +1,33 @@
 mod config;
 mod error;
+mod jwks;
 mod notify;
+mod presence;
 mod sse;
 pub use notify::{setup_pg_listener, AppEvent};
-use std::{ops::Deref, sync::Arc};
+pub use presence::{setup_presence_ticker, Presence, PresenceStatus, UserPresence};
+use std::{
+    ops::Deref,
+    sync::{Arc, RwLock},
+};
 
 use axum::{
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
 use chat_core::{
     middleware::{auth::verify_token, TokenVerify},
     utils::jwt::DecodingKey,
-    User,
+    Principal, User,
 };
-pub use config::AppConfig;
+pub use config::{AppConfig, PresenceConfig};
 use dashmap::DashMap;
 use error::AppError;
+use jwks::setup_jwks_refresher;
+use presence::{get_presence_handler, presence_heartbeat_handler};
+use sqlx::PgPool;
 use sse::sse_handler;
 use tokio::sync::broadcast;
 
@@ -29,8 +38,10 @@ const INDEX_HTML: &str = include_str!("../index.html");
 pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub config: AppConfig,
-    pub dk: DecodingKey,
+    pub dk: RwLock<DecodingKey>,
     pub users: UserMap,
+    pub pool: PgPool,
+    pub presence: Presence,
 }
 impl Deref for AppState {
     type Target = AppStateInner;
@@ -40,27 +51,80 @@ impl Deref for AppState {
 }
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
-        let user = self.dk.verify(token);
-        Ok(user?)
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        let (user, _) = self.verify_principal(token).await?;
+        Ok(user)
+    }
+
+    async fn verify_principal(&self, token: &str) -> Result<(User, Principal), Self::Error> {
+        let claims = self
+            .dk
+            .read()
+            .expect("dk lock poisoned")
+            .verify_claims(token)?;
+        let jti = claims.jwt_id.as_deref().ok_or(AppError::TokenRevoked)?;
+        // revoked tokens are written by chat_server on sign out
+        let revoked = sqlx::query("select 1 from revoked_tokens where jti = $1")
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+        if revoked.is_some() {
+            return Err(AppError::TokenRevoked);
+        }
+        // so are the revoked sessions(devices)
+        if let Some(session_id) = claims.custom.session_id {
+            let revoked =
+                sqlx::query("select 1 from sessions where id = $1 and revoked_at is not null")
+                    .bind(session_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            if revoked.is_some() {
+                return Err(AppError::TokenRevoked);
+            }
+        }
+        // deleted users are deactivated as well
+        let deactivated =
+            sqlx::query("select 1 from users where id = $1 and deactivated_at is not null")
+                .bind(claims.custom.user.id)
+                .fetch_optional(&self.pool)
+                .await?;
+        if deactivated.is_some() {
+            return Err(AppError::AccountDeactivated);
+        }
+        let principal = Principal::from_claims(claims.custom.role, &claims);
+        Ok((claims.custom.user, principal))
     }
 }
 
 impl AppState {
     pub fn new(config: AppConfig) -> Result<Self, AppError> {
-        let decoding_pem = include_str!("../fixtures/decoding_key.pem");
-        let dk = DecodingKey::load(decoding_pem)?;
+        // keys are fetched by `setup_jwks_refresher` if jwks_url is set
+        let dk = match &config.auth.pk {
+            Some(pem) => DecodingKey::load(pem)?,
+            None => DecodingKey::default(),
+        };
         let users = Arc::new(DashMap::new());
-        Ok(Self(Arc::new(AppStateInner { dk, config, users })))
+        let pool = PgPool::connect_lazy(&config.server.db_url)?;
+        Ok(Self(Arc::new(AppStateInner {
+            dk: RwLock::new(dk),
+            config,
+            users,
+            pool,
+            presence: Presence::default(),
+        })))
     }
 }
 
 pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
     let state = AppState::new(config).expect("failed to create app state");
     setup_pg_listener(state.clone()).await?;
+    setup_jwks_refresher(state.clone()).await?;
+    setup_presence_ticker(state.clone()).await?;
 
     let router = Router::new()
         .route("/events", get(sse_handler))
+        .route("/presence", get(get_presence_handler))
+        .route("/presence/heartbeat", post(presence_heartbeat_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/", get(index_handler))
         .with_state(state.clone());
@@ -1,10 +1,10 @@
 use std::{collections::HashSet, sync::Arc};
 
-use crate::AppState;
+use crate::{presence::UserPresence, AppState};
 use chat_core::{Chat, Message};
 use jwt_simple::reexports::serde_json;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::{postgres::PgListener, PgPool};
 use tokio_stream::StreamExt;
 use tracing::{info, warn};
 
@@ -13,8 +13,57 @@ use tracing::{info, warn};
 pub enum AppEvent {
     NewChat(Chat),
     AddToChat(Chat),
+    /// the user is removed from the chat
     RemoveFromChat(Chat),
+    ChatDeleted(DeletedChat),
+    /// the chat is archived, it's read-only until it's restored
+    ChatArchived(Chat),
     NewMessage(Message),
+    /// a session of the user is revoked, the SSE connection of it is closed
+    SessionRevoked(SessionRevoked),
+    /// a user sharing a workspace updated its profile, refresh the cached `ChatUser`
+    ProfileUpdated(UserProfile),
+    /// the user is deactivated or deleted, all its SSE connections are closed after it
+    UserDeactivated(UserDeactivated),
+    /// a user sharing a workspace went online, away or offline
+    PresenceChanged(UserPresence),
+    /// a member of the chat started or stopped typing, it's not stored
+    Typing(Typing),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub title: Option<String>,
+    pub timezone: Option<String>,
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRevoked {
+    pub user_id: u64,
+    pub session_id: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletedChat {
+    pub id: i64,
+    pub ws_id: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserDeactivated {
+    pub user_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Typing {
+    pub chat_id: u64,
+    pub user_id: u64,
+    /// false once the signal expires
+    pub typing: bool,
 }
 
 struct Notification {
@@ -22,37 +71,72 @@ struct Notification {
     event: Arc<AppEvent>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChatOp {
+    Insert,
+    Update,
+    /// more users removed by the previous update
+    Remove,
+    Archive,
+    Delete,
+}
+
+/// the members are not in the payload, they're loaded by the chat id
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatUpdated {
-    op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+    op: ChatOp,
+    chat_id: i64,
+    ws_id: i64,
+    removed: Vec<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct MessageCreated {
-    members: Vec<u64>,
     message: Message,
 }
 
+/// the users sharing a workspace are loaded by the user id
+#[derive(Debug, Serialize, Deserialize)]
+struct ProfileUpdated {
+    user: UserProfile,
+}
+
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
     println!("Connecting to database: {}", state.config.server.db_url);
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("message_added").await?;
+    listener.listen("session_revoked").await?;
+    listener.listen("profile_updated").await?;
+    listener.listen("user_deactivated").await?;
+    listener.listen("chat_typing").await?;
 
     let mut stream = listener.into_stream();
     tokio::spawn(async move {
         while let Some(Ok(notify)) = stream.next().await {
             info!("Received notification: {:?}", notify);
-            let notification = Notification::load(notify.channel(), notify.payload()).unwrap();
+            let notifications =
+                match Notification::load(notify.channel(), notify.payload(), &state.pool).await {
+                    Ok(notifications) => notifications,
+                    Err(e) => {
+                        warn!("Failed to load notification {:?}: {:?}", notify, e);
+                        continue;
+                    }
+                };
             let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
+            for notification in notifications {
+                for user_id in notification.user_ids {
+                    if let Some(tx) = users.get(&user_id) {
+                        info!("Sending notification to user {}", user_id);
 
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {:?}", user_id, e);
+                        if let Err(e) = tx.send(notification.event.clone()) {
+                            warn!("Failed to send notification to user {}: {:?}", user_id, e);
+                        }
+                    }
+                    // dropping the sender ends the streams once the event is received
+                    if let AppEvent::UserDeactivated(_) = notification.event.as_ref() {
+                        users.remove(&user_id);
                     }
                 }
             }
@@ -63,52 +147,196 @@ pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
 }
 
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
+    fn new(user_ids: HashSet<u64>, event: AppEvent) -> Self {
+        Self {
+            user_ids,
+            event: Arc::new(event),
+        }
+    }
+
+    /// a chat update may tell the members and the removed users different events
+    async fn load(r#type: &str, payload: &str, pool: &PgPool) -> anyhow::Result<Vec<Self>> {
         match r#type {
             "chat_updated" => {
                 let payload = serde_json::from_str::<ChatUpdated>(payload)?;
                 info!("Chat updated: {:?}", payload);
-                let user_ids = get_affected_user_ids(payload.old.as_ref(), payload.new.as_ref());
-                let event = match payload.op.to_lowercase().as_str() {
-                    "insert" => AppEvent::NewChat(payload.new.expect("new should exist")),
-                    "update" => AppEvent::AddToChat(payload.new.expect("new should exist")),
-                    "delete" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
-                    _ => return Err(anyhow::anyhow!("Invalid operation")),
+                // the chat may be deleted already, its deletion is notified too
+                let chat = match payload.op {
+                    ChatOp::Delete => None,
+                    _ => fetch_chat(pool, payload.chat_id).await?,
                 };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+                Ok(chat_notifications(payload, chat))
             }
             "message_added" => {
                 let payload = serde_json::from_str::<MessageCreated>(payload)?;
                 info!("Message created: {:?}", payload);
-                // let user_ids = payload.members.iter().map(|id| *id).collect();
-                let user_ids = payload.members.iter().copied().collect();
+                let user_ids = fetch_members(pool, payload.message.chat_id).await?;
                 let event = AppEvent::NewMessage(payload.message);
-                Ok(Self {
+                Ok(vec![Self::new(user_ids, event)])
+            }
+            "session_revoked" => {
+                let payload = serde_json::from_str::<SessionRevoked>(payload)?;
+                info!("Session revoked: {:?}", payload);
+                let user_ids = HashSet::from([payload.user_id]);
+                Ok(vec![Self::new(user_ids, AppEvent::SessionRevoked(payload))])
+            }
+            "profile_updated" => {
+                let payload = serde_json::from_str::<ProfileUpdated>(payload)?;
+                info!("Profile updated: {:?}", payload);
+                let user_ids = fetch_workspace_peers(pool, payload.user.id).await?;
+                Ok(vec![Self::new(
+                    user_ids,
+                    AppEvent::ProfileUpdated(payload.user),
+                )])
+            }
+            "user_deactivated" => {
+                let payload = serde_json::from_str::<UserDeactivated>(payload)?;
+                info!("User deactivated: {:?}", payload);
+                let user_ids = HashSet::from([payload.user_id]);
+                Ok(vec![Self::new(
                     user_ids,
-                    event: Arc::new(event),
-                })
+                    AppEvent::UserDeactivated(payload),
+                )])
+            }
+            "chat_typing" => {
+                let payload = serde_json::from_str::<Typing>(payload)?;
+                let mut user_ids = fetch_members(pool, payload.chat_id as _).await?;
+                user_ids.remove(&payload.user_id);
+                Ok(vec![Self::new(user_ids, AppEvent::Typing(payload))])
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
 }
 
-fn get_affected_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
-    match (old, new) {
-        (Some(old), Some(new)) => {
-            let old_users: HashSet<u64> = old.members.iter().map(|id| *id as u64).collect();
-            let new_users: HashSet<u64> = new.members.iter().map(|id| *id as u64).collect();
-            if old_users != new_users {
-                old_users.union(&new_users).copied().collect()
-            } else {
-                HashSet::new()
-            }
+async fn fetch_chat(pool: &PgPool, id: i64) -> anyhow::Result<Option<Chat>> {
+    let chat = sqlx::query_as(
+        r#"
+        SELECT id, ws_id, name, type, chat_members_of(id) AS members, archived_at, created_at
+        FROM chats
+        WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(chat)
+}
+
+async fn fetch_members(pool: &PgPool, chat_id: i64) -> anyhow::Result<HashSet<u64>> {
+    let members: Vec<(i64,)> =
+        sqlx::query_as("SELECT user_id FROM chat_members WHERE chat_id = $1")
+            .bind(chat_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(members.into_iter().map(|(id,)| id as u64).collect())
+}
+
+/// the users sharing a workspace with the user, the user itself included
+async fn fetch_workspace_peers(pool: &PgPool, user_id: i64) -> anyhow::Result<HashSet<u64>> {
+    let members: Vec<(i64,)> = sqlx::query_as(
+        r#"
+        select distinct m.user_id from workspace_members m
+        where m.ws_id in (select ws_id from workspace_members where user_id = $1)
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(members.into_iter().map(|(id,)| id as u64).collect())
+}
+
+fn member_ids(chat: &Chat) -> HashSet<u64> {
+    chat.members.iter().map(|id| *id as u64).collect()
+}
+
+/// archiving is told to all members, otherwise the removed users get `RemoveFromChat`
+/// and the members get `AddToChat`, renamed and restored chats included
+fn chat_notifications(payload: ChatUpdated, chat: Option<Chat>) -> Vec<Notification> {
+    let removed: HashSet<u64> = payload.removed.into_iter().collect();
+    if payload.op == ChatOp::Delete {
+        let event = AppEvent::ChatDeleted(DeletedChat {
+            id: payload.chat_id,
+            ws_id: payload.ws_id,
+        });
+        return vec![Notification::new(removed, event)];
+    }
+    let Some(chat) = chat else {
+        return vec![];
+    };
+    let mut notifications = vec![];
+    if !removed.is_empty() {
+        notifications.push(Notification::new(
+            removed,
+            AppEvent::RemoveFromChat(chat.clone()),
+        ));
+    }
+    let members = member_ids(&chat);
+    match payload.op {
+        ChatOp::Insert => notifications.push(Notification::new(members, AppEvent::NewChat(chat))),
+        ChatOp::Update => notifications.push(Notification::new(members, AppEvent::AddToChat(chat))),
+        ChatOp::Archive => {
+            notifications.push(Notification::new(members, AppEvent::ChatArchived(chat)))
+        }
+        ChatOp::Remove | ChatOp::Delete => {}
+    }
+    notifications
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn chat(members: &[i64]) -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            r#type: chat_core::ChatType::Group,
+            name: None,
+            members: members.to_vec(),
+            archived_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn payload(op: ChatOp, removed: &[u64]) -> ChatUpdated {
+        ChatUpdated {
+            op,
+            chat_id: 1,
+            ws_id: 1,
+            removed: removed.to_vec(),
         }
-        (Some(old), None) => old.members.iter().map(|id| *id as u64).collect(),
-        (None, Some(new)) => new.members.iter().map(|id| *id as u64).collect(),
-        (None, None) => HashSet::new(),
+    }
+
+    #[test]
+    fn chat_update_should_tell_removed_users() {
+        let notifications = chat_notifications(payload(ChatOp::Update, &[3]), Some(chat(&[1, 2])));
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].user_ids, HashSet::from([3]));
+        assert!(matches!(
+            notifications[0].event.as_ref(),
+            AppEvent::RemoveFromChat(_)
+        ));
+        assert_eq!(notifications[1].user_ids, HashSet::from([1, 2]));
+
+        // the members are told only once
+        let notifications = chat_notifications(payload(ChatOp::Remove, &[4]), Some(chat(&[1, 2])));
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].user_ids, HashSet::from([4]));
+
+        let notifications = chat_notifications(payload(ChatOp::Archive, &[]), Some(chat(&[1, 2])));
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(
+            notifications[0].event.as_ref(),
+            AppEvent::ChatArchived(_)
+        ));
+
+        let notifications = chat_notifications(payload(ChatOp::Delete, &[1, 2]), None);
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(
+            notifications[0].event.as_ref(),
+            AppEvent::ChatDeleted(DeletedChat { id: 1, .. })
+        ));
     }
 }
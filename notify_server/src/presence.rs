@@ -0,0 +1,266 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{error::AppError, AppEvent, AppState};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// most users a presence query can ask for
+const MAX_PRESENCE_QUERY: usize = 100;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    /// has an open `/events` stream and was active recently
+    Online,
+    /// has an open `/events` stream, but idle for `presence.away_after` seconds
+    Away,
+    #[default]
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserPresence {
+    pub user_id: u64,
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PresenceQuery {
+    /// comma separated user ids, e.g. `2,3,4`
+    ids: String,
+}
+
+#[derive(Debug)]
+struct Connections {
+    count: usize,
+    last_active: Instant,
+    status: PresenceStatus,
+}
+
+/// connection counts of the users with open `/events` streams
+#[derive(Debug, Default)]
+pub struct Presence(DashMap<u64, Connections>);
+
+/// held by an `/events` stream, the connection is counted until it's dropped
+pub(crate) struct PresenceGuard {
+    state: AppState,
+    user_id: u64,
+}
+
+impl Presence {
+    pub fn status(&self, user_id: u64) -> PresenceStatus {
+        self.0.get(&user_id).map(|c| c.status).unwrap_or_default()
+    }
+
+    /// the new status if it's changed
+    fn connect(&self, user_id: u64, now: Instant) -> Option<PresenceStatus> {
+        let mut c = self.0.entry(user_id).or_insert(Connections {
+            count: 0,
+            last_active: now,
+            status: PresenceStatus::Offline,
+        });
+        c.count += 1;
+        c.last_active = now;
+        c.set_status(PresenceStatus::Online)
+    }
+
+    fn disconnect(&self, user_id: u64) -> Option<PresenceStatus> {
+        let removed = self.0.remove_if_mut(&user_id, |_, c| {
+            c.count = c.count.saturating_sub(1);
+            c.count == 0
+        });
+        removed.map(|_| PresenceStatus::Offline)
+    }
+
+    /// the user is active, an away user is back online
+    fn touch(&self, user_id: u64, now: Instant) -> Option<PresenceStatus> {
+        let mut c = self.0.get_mut(&user_id)?;
+        c.last_active = now;
+        c.set_status(PresenceStatus::Online)
+    }
+
+    /// users idle for `away_after` become away
+    fn expire(&self, now: Instant, away_after: Duration) -> Vec<u64> {
+        self.0
+            .iter_mut()
+            .filter_map(|mut c| {
+                let idle = now.saturating_duration_since(c.last_active) >= away_after;
+                let changed = idle && c.set_status(PresenceStatus::Away).is_some();
+                changed.then(|| *c.key())
+            })
+            .collect()
+    }
+}
+
+impl Connections {
+    fn set_status(&mut self, status: PresenceStatus) -> Option<PresenceStatus> {
+        (self.status != status).then(|| {
+            self.status = status;
+            status
+        })
+    }
+}
+
+impl AppState {
+    /// count an `/events` connection of the user
+    pub(crate) fn connect_presence(&self, user_id: u64) -> PresenceGuard {
+        if let Some(status) = self.presence.connect(user_id, Instant::now()) {
+            self.spawn_broadcast_presence(user_id, status);
+        }
+        PresenceGuard {
+            state: self.clone(),
+            user_id,
+        }
+    }
+
+    /// tell the users sharing a workspace with the user, the user itself included
+    async fn broadcast_presence(
+        &self,
+        user_id: u64,
+        status: PresenceStatus,
+    ) -> Result<(), AppError> {
+        let members: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            select distinct m.user_id from workspace_members m
+            where m.ws_id in (select ws_id from workspace_members where user_id = $1)
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        let event = Arc::new(AppEvent::PresenceChanged(UserPresence { user_id, status }));
+        for (id,) in members {
+            if let Some(tx) = self.users.get(&(id as u64)) {
+                let _ = tx.send(event.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_broadcast_presence(&self, user_id: u64, status: PresenceStatus) {
+        info!("User {} is {:?}", user_id, status);
+        let state = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = state.broadcast_presence(user_id, status).await {
+                warn!("Failed to broadcast presence of user {}: {:?}", user_id, e);
+            }
+        });
+    }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        if let Some(status) = self.state.presence.disconnect(self.user_id) {
+            self.state.spawn_broadcast_presence(self.user_id, status);
+        }
+    }
+}
+
+/// mark the idle users away periodically
+pub async fn setup_presence_ticker(state: AppState) -> anyhow::Result<()> {
+    let away_after = Duration::from_secs(state.config.presence.away_after);
+    let mut interval = tokio::time::interval((away_after / 4).max(Duration::from_secs(1)));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            for user_id in state.presence.expire(Instant::now(), away_after) {
+                state.spawn_broadcast_presence(user_id, PresenceStatus::Away);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// keep the current user online, call it on user activity
+pub(crate) async fn presence_heartbeat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let user_id = user.id as u64;
+    if let Some(status) = state.presence.touch(user_id, Instant::now()) {
+        state.spawn_broadcast_presence(user_id, status);
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// presence of the given users, only the users sharing a workspace with the current user are returned
+pub(crate) async fn get_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(query): Query<PresenceQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let ids = query
+        .ids
+        .split(',')
+        .map(|id| id.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| AppError::InvalidInput(format!("invalid user ids: {}", query.ids)))?;
+    if ids.len() > MAX_PRESENCE_QUERY {
+        return Err(AppError::InvalidInput(format!(
+            "at most {} users can be queried",
+            MAX_PRESENCE_QUERY
+        )));
+    }
+    let visible: Vec<(i64,)> = sqlx::query_as(
+        r#"
+        select distinct m.user_id from workspace_members m
+        where m.user_id = any($1)
+            and m.ws_id in (select ws_id from workspace_members where user_id = $2)
+        order by m.user_id
+        "#,
+    )
+    .bind(&ids)
+    .bind(user.id)
+    .fetch_all(&state.pool)
+    .await?;
+    let ret: Vec<UserPresence> = visible
+        .into_iter()
+        .map(|(id,)| UserPresence {
+            user_id: id as u64,
+            status: state.presence.status(id as u64),
+        })
+        .collect();
+    Ok(Json(ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presence_should_follow_connections() {
+        let presence = Presence::default();
+        let now = Instant::now();
+        let away_after = Duration::from_secs(300);
+        assert_eq!(presence.connect(1, now), Some(PresenceStatus::Online));
+        // a second device doesn't change it
+        assert_eq!(presence.connect(1, now), None);
+        assert_eq!(presence.status(2), PresenceStatus::Offline);
+
+        assert!(presence.expire(now + away_after / 2, away_after).is_empty());
+        assert_eq!(presence.expire(now + away_after, away_after), vec![1]);
+        assert_eq!(presence.status(1), PresenceStatus::Away);
+        assert!(presence.expire(now + away_after * 2, away_after).is_empty());
+        assert_eq!(
+            presence.touch(1, now + away_after * 2),
+            Some(PresenceStatus::Online)
+        );
+
+        assert_eq!(presence.disconnect(1), None);
+        assert_eq!(presence.status(1), PresenceStatus::Online);
+        assert_eq!(presence.disconnect(1), Some(PresenceStatus::Offline));
+        assert_eq!(presence.status(1), PresenceStatus::Offline);
+        assert_eq!(presence.touch(1, now), None);
+    }
+}
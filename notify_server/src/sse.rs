@@ -8,7 +8,7 @@ use axum::{
     Extension,
 };
 
-use chat_core::User;
+use chat_core::{Principal, User};
 use futures::stream::Stream;
 use jwt_simple::reexports::serde_json;
 use tokio::sync::broadcast;
@@ -22,6 +22,7 @@ const CHANNEL_CAPACITY: usize = 256;
 pub(crate) async fn sse_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    principal: Principal,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
     let user_id = user.id as u64;
     // let user_id = 2;
@@ -34,18 +35,37 @@ pub(crate) async fn sse_handler(
         rx
     };
 
-    let broadcast_stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match &v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
-        let v = serde_json::to_string(&v).expect("failed to serialize event");
-        debug!("sending event {} :{:?}", name, v);
-
-        Ok(Event::default().data(v).event(name))
-    });
+    // the user is online while the stream is alive
+    let presence = state.connect_presence(user_id);
+
+    // the stream ends once the session of the connection is revoked
+    let session_id = principal.session_id;
+    let broadcast_stream = BroadcastStream::new(rx)
+        .filter_map(|v| v.ok())
+        .take_while(move |v| match v.as_ref() {
+            AppEvent::SessionRevoked(e) => Some(e.session_id) != session_id,
+            _ => true,
+        })
+        .map(move |v| {
+            let _presence = &presence;
+            let name = match &v.as_ref() {
+                AppEvent::NewChat(_) => "NewChat",
+                AppEvent::AddToChat(_) => "AddToChat",
+                AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+                AppEvent::ChatDeleted(_) => "ChatDeleted",
+                AppEvent::ChatArchived(_) => "ChatArchived",
+                AppEvent::NewMessage(_) => "NewMessage",
+                AppEvent::SessionRevoked(_) => "SessionRevoked",
+                AppEvent::ProfileUpdated(_) => "ProfileUpdated",
+                AppEvent::UserDeactivated(_) => "UserDeactivated",
+                AppEvent::PresenceChanged(_) => "PresenceChanged",
+                AppEvent::Typing(_) => "Typing",
+            };
+            let v = serde_json::to_string(&v).expect("failed to serialize event");
+            debug!("sending event {} :{:?}", name, v);
+
+            Ok(Event::default().data(v).event(name))
+        });
 
     Sse::new(broadcast_stream).keep_alive(
         axum::response::sse::KeepAlive::new()